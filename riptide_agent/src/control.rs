@@ -0,0 +1,139 @@
+//! Local control socket, allowing the cli to query and manage the running agent.
+
+use std::{os::unix::fs::PermissionsExt, path::Path};
+
+use log::{debug, error, info, warn};
+use riptide_config::Config;
+use riptide_control::{decode, encode, AgentStatus, Request, Response};
+use tokio::{
+    fs,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::{error::AgentError, Context};
+
+/// Carry out a single request from the cli.
+async fn handle_request(ctx: &Context, request: Request) -> Response {
+    match request {
+        Request::Status => {
+            let session = ctx.session.read().await;
+            let (uploads_running, uploads_queued) = ctx.scheduler.counts();
+            Response::Status(AgentStatus {
+                pid: std::process::id(),
+                version: String::from(env!("CARGO_PKG_VERSION")),
+                connected: session.is_connected(),
+                connection_uptime_seconds: session.uptime().as_secs(),
+                reconnects: session.reconnects(),
+                last_disconnect_reason: session.last_disconnect_reason().map(String::from),
+                last_error: session.last_error().map(String::from),
+                rejected: session.rejection().map(String::from),
+                paused: ctx.scheduler.is_paused(),
+                shutting_down: ctx.shutdown.is_cancelled(),
+                uploads_running,
+                uploads_queued,
+            })
+        }
+        Request::Uploads => Response::Uploads(ctx.uploads.list()),
+        Request::Reload => match crate::handle_reload(ctx).await {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Error(e.to_string()),
+        },
+        Request::Pause => {
+            info!("Uploads paused by cli");
+            ctx.scheduler.set_paused(true);
+            Response::Ok
+        }
+        Request::Resume => {
+            info!("Uploads resumed by cli");
+            ctx.scheduler.set_paused(false);
+            Response::Ok
+        }
+        Request::SweepExpired => match crate::remove_expired_shares(ctx).await {
+            Ok(removed) => Response::Swept(removed),
+            Err(e) => Response::Error(e.to_string()),
+        },
+    }
+}
+
+/// Serve requests from a single cli connection until it is closed.
+async fn handle_connection(ctx: Context, stream: UnixStream) -> Result<(), AgentError> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let response = match decode::<Request>(&line) {
+            Ok(request) => {
+                debug!("Control request: {:?}", request);
+                handle_request(&ctx, request).await
+            }
+            Err(e) => Response::Error(e.to_string()),
+        };
+
+        let data = encode(&response).map_err(|e| AgentError::Other(Box::new(e)))?;
+        writer.write_all(&data).await?;
+    }
+
+    Ok(())
+}
+
+/// Bind the control socket, replacing a stale socket left behind by an agent that didn't exit cleanly.
+async fn bind(path: &Path) -> Result<UnixListener, AgentError> {
+    if fs::metadata(path).await.is_ok() {
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(AgentError::Other(
+                format!("another agent is already listening on {}", path.display()).into(),
+            ));
+        }
+        fs::remove_file(path).await?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    // only the user running the agent may control it
+    fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+    Ok(listener)
+}
+
+/// Start listening on the control socket, returns `None` if the socket can't be used.
+pub async fn listen() -> Option<UnixListener> {
+    let path = Config::control_socket_location();
+    match bind(&path).await {
+        Ok(listener) => {
+            info!("Listening for control requests on {}", path.display());
+            Some(listener)
+        }
+        Err(e) => {
+            error!(
+                "Unable to listen on control socket {}, the cli will fall back to the database: {}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Serve control requests from the cli, requests are served until the agent exits.
+pub async fn serve(ctx: Context, listener: UnixListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let ctx = ctx.clone();
+                tokio::task::spawn(async move {
+                    if let Err(e) = handle_connection(ctx, stream).await {
+                        debug!("Control connection closed: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept control connection: {}", e),
+        }
+    }
+}
+
+/// Remove the control socket as the agent exits, so the cli doesn't try to use it.
+pub async fn remove_socket() {
+    let path = Config::control_socket_location();
+    if let Err(e) = fs::remove_file(&path).await {
+        warn!("Failed to remove control socket {}: {}", path.display(), e);
+    }
+}
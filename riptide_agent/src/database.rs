@@ -0,0 +1,177 @@
+//! A long-lived connection to the shares database, owned by a dedicated worker thread.
+//!
+//! Opening the database runs any pending migrations, so rather than doing that for every request the
+//! connection is opened once at startup and queries are sent to the worker over a channel. If a query
+//! fails because of the connection itself, the connection is dropped and re-opened for the next one, while
+//! ordinary errors such as a constraint violation leave it be.
+
+use std::error::Error;
+
+use log::{debug, error};
+use riptide_database::{establish_connection, is_connection_error, SqliteConnection};
+use tokio::sync::{mpsc, oneshot};
+
+/// The number of queries which may be waiting on the worker before callers have to wait.
+const QUEUE_SIZE: usize = 64;
+
+/// Errors returned by the database, matching those of `riptide_database`.
+pub type DatabaseError = Box<dyn Error + Send + Sync + 'static>;
+
+/// What a query means for the connection it was run on.
+enum Outcome {
+    /// The query succeeded, or failed for a reason of its own.
+    Done,
+    /// The connection is no longer usable and should be re-opened.
+    ConnectionLost,
+}
+
+type Query = Box<dyn FnOnce(Result<&mut SqliteConnection, DatabaseError>) -> Outcome + Send>;
+
+enum Job {
+    Query(Query),
+    SetLocation(String),
+}
+
+/// Handle to the database worker, queries are run one at a time in the order they are sent.
+#[derive(Debug)]
+pub struct Database {
+    jobs: mpsc::Sender<Job>,
+}
+
+/// Open the database at `location`, logging rather than returning any error.
+fn open(location: &str) -> Option<SqliteConnection> {
+    match establish_connection(location) {
+        Ok(conn) => Some(conn),
+        Err(e) => {
+            error!("Unable to open database {}: {}", location, e);
+            None
+        }
+    }
+}
+
+/// Owns the connection, running queries until every handle has been dropped.
+fn worker(mut location: String, mut jobs: mpsc::Receiver<Job>) {
+    let mut conn = open(&location);
+    while let Some(job) = jobs.blocking_recv() {
+        match job {
+            Job::SetLocation(new_location) => {
+                if new_location != location {
+                    debug!("Database moved to {}, reconnecting", new_location);
+                    location = new_location;
+                    conn = open(&location);
+                }
+            }
+            Job::Query(query) => {
+                if conn.is_none() {
+                    match establish_connection(&location) {
+                        Ok(c) => conn = Some(c),
+                        Err(e) => {
+                            query(Err(e));
+                            continue;
+                        }
+                    }
+                }
+
+                if let Outcome::ConnectionLost = query(Ok(conn.as_mut().unwrap())) {
+                    debug!("Database connection lost, reconnecting for the next query");
+                    conn = None;
+                }
+            }
+        }
+    }
+}
+
+impl Database {
+    /// Start the worker for the database at `location`, opening it and running any pending migrations.
+    pub fn start(location: String) -> Database {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        std::thread::Builder::new()
+            .name(String::from("database"))
+            .spawn(move || worker(location, rx))
+            .expect("failed to spawn database worker");
+        Database { jobs: tx }
+    }
+
+    /// Run a query against the database.
+    pub async fn run<T, F>(&self, f: F) -> Result<T, DatabaseError>
+    where
+        F: FnOnce(&mut SqliteConnection) -> Result<T, DatabaseError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let query: Query = Box::new(move |conn| {
+            let res = conn.and_then(f);
+            let outcome = match &res {
+                Err(e) if is_connection_error(&**e) => Outcome::ConnectionLost,
+                _ => Outcome::Done,
+            };
+            if tx.send(res).is_err() {
+                debug!("Database query finished after the caller went away");
+            }
+            outcome
+        });
+
+        self.jobs
+            .send(Job::Query(query))
+            .await
+            .map_err(|_| "database worker has stopped")?;
+        rx.await.map_err(|_| "database worker has stopped")?
+    }
+
+    /// Point the worker at a new database location, e.g. after the configuration has been reloaded.
+    pub async fn set_location(&self, location: String) {
+        if self.jobs.send(Job::SetLocation(location)).await.is_err() {
+            error!("Database worker has stopped, unable to change location");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use riptide_database::{Share, ShareKind};
+
+    use super::Database;
+
+    #[tokio::test]
+    async fn test_queries_share_a_connection() {
+        // an in-memory database only lives as long as its connection
+        let database = Database::start(String::from(":memory:"));
+
+        let share = Arc::new(Share {
+            file_id: 1,
+            exp: i64::MAX,
+            crt: 0,
+            file_size: 10,
+            user_name: String::from("user"),
+            file_name: String::from("file.txt"),
+            broken_reason: None,
+            max_downloads: None,
+            downloads: 0,
+            one_time: false,
+            archive_format: None,
+            kind: ShareKind::File,
+            source_path: None,
+            archive_options: None,
+            source_fingerprint: None,
+        });
+        let first = share.clone();
+        database
+            .run(move |conn| riptide_database::insert_share(conn, &first))
+            .await
+            .unwrap();
+
+        // a failed query keeps the connection, and so the database, around
+        let res = database
+            .run(move |conn| riptide_database::insert_share(conn, &share))
+            .await;
+        assert!(res.is_err());
+
+        let share = database
+            .run(|conn| riptide_database::get_share_by_id(conn, &1))
+            .await
+            .unwrap();
+        assert_eq!(share.map(|s| s.file_name), Some(String::from("file.txt")));
+    }
+}
@@ -3,14 +3,41 @@ use tokio::task::JoinError;
 #[derive(Debug)]
 pub enum AgentError {
     ReadFile(std::io::Error),
-    Http(ureq::Error),
+    Http(reqwest::Error),
+    UploadRejected(reqwest::StatusCode),
+    UploadStalled,
+    InvalidRange(String),
+    SourceChanged(String),
+    Cancelled,
     JoinError(JoinError),
     TokioError(tokio_tungstenite::tungstenite::Error),
     FrameworkError(ws_com_framework::Error),
     BadFrame(String),
+    ConnectionDead(String),
     Other(Box<dyn std::error::Error + 'static + Send + Sync>),
 }
 
+impl AgentError {
+    /// A short name for the kind of error, used to label metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AgentError::ReadFile(_) => "read_file",
+            AgentError::Http(_) => "http",
+            AgentError::UploadRejected(_) => "rejected",
+            AgentError::UploadStalled => "stalled",
+            AgentError::InvalidRange(_) => "invalid_range",
+            AgentError::SourceChanged(_) => "source_changed",
+            AgentError::Cancelled => "cancelled",
+            AgentError::JoinError(_) => "join",
+            AgentError::TokioError(_) => "websocket",
+            AgentError::FrameworkError(_) => "framework",
+            AgentError::BadFrame(_) => "bad_frame",
+            AgentError::ConnectionDead(_) => "connection_dead",
+            AgentError::Other(_) => "other",
+        }
+    }
+}
+
 impl From<tokio_tungstenite::tungstenite::Error> for AgentError {
     fn from(t: tokio_tungstenite::tungstenite::Error) -> Self {
         Self::TokioError(t)
@@ -23,8 +50,8 @@ impl From<std::io::Error> for AgentError {
     }
 }
 
-impl From<ureq::Error> for AgentError {
-    fn from(e: ureq::Error) -> AgentError {
+impl From<reqwest::Error> for AgentError {
+    fn from(e: reqwest::Error) -> AgentError {
         AgentError::Http(e)
     }
 }
@@ -52,6 +79,15 @@ impl std::fmt::Display for AgentError {
         match self {
             AgentError::ReadFile(e) => write!(f, "Unable to read file: {}", e),
             AgentError::Http(e) => write!(f, "Unable to establish http connection: {}", e),
+            AgentError::UploadRejected(s) => write!(f, "Upload rejected by server: {}", s),
+            AgentError::UploadStalled => {
+                write!(f, "Upload stalled, no progress made before timeout")
+            }
+            AgentError::InvalidRange(e) => write!(f, "Invalid upload range requested: {}", e),
+            AgentError::SourceChanged(e) => {
+                write!(f, "Content has changed since it was shared: {}", e)
+            }
+            AgentError::Cancelled => write!(f, "Operation was cancelled"),
             AgentError::JoinError(e) => {
                 write!(f, "Unable to join process, should never happen: {}", e)
             }
@@ -59,6 +95,7 @@ impl std::fmt::Display for AgentError {
             AgentError::TokioError(e) => write!(f, "tokio error occured: {}", e),
             AgentError::FrameworkError(e) => write!(f, "erorr occured in framework: {}", e),
             AgentError::BadFrame(e) => write!(f, "program got a bad or unexpected ws frame: {}", e),
+            AgentError::ConnectionDead(e) => write!(f, "Connection to server is dead: {}", e),
         }
     }
 }
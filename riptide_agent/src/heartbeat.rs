@@ -0,0 +1,231 @@
+//! Detection of dead websocket connections.
+//!
+//! A connection can die without either side noticing, e.g. when a laptop sleeps or a NAT mapping times out,
+//! in which case the socket never reports an error and we would wait on it forever. To catch this we ping the
+//! server and expect a pong back in time, and give up on connections we haven't heard anything on for a while.
+//!
+//! We also watch for the system clock jumping, which happens when the machine resumes from sleep. Any
+//! connection that was open beforehand is unlikely to have survived, so it is better to reconnect straight away.
+
+use std::time::{Duration, SystemTime};
+
+use riptide_config::Config;
+use tokio::time::Instant;
+
+/// How often to check the clock for jumps, when nothing else is due.
+const CLOCK_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How far the clock may drift between checks before we assume the system was suspended.
+const CLOCK_JUMP_THRESHOLD: Duration = Duration::from_secs(30);
+
+/// What the connection should do after a heartbeat check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Beat {
+    /// Nothing to do until the next check.
+    Idle,
+    /// Send a ping with this payload.
+    Ping(Vec<u8>),
+    /// The connection should be considered dead, for the given reason.
+    Dead(String),
+}
+
+/// Heartbeat state for a single websocket connection.
+#[derive(Debug)]
+pub struct Heartbeat {
+    ping_interval: Option<Duration>,
+    pong_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    pings_sent: u64,
+    last_ping: Instant,
+    /// The payload of the outstanding ping, and when its pong is due.
+    awaiting_pong: Option<(Vec<u8>, Instant)>,
+    last_received: Instant,
+    last_check: (Instant, SystemTime),
+}
+
+/// Treat a timeout of zero as disabled.
+fn enabled(seconds: u64) -> Option<Duration> {
+    (seconds > 0).then(|| Duration::from_secs(seconds))
+}
+
+impl Heartbeat {
+    /// Start tracking a connection which has just been established.
+    pub fn new(config: &Config, now: Instant, wall: SystemTime) -> Heartbeat {
+        Heartbeat {
+            ping_interval: enabled(*config.ping_interval_seconds()),
+            pong_timeout: Duration::from_secs(*config.pong_timeout_seconds()),
+            idle_timeout: enabled(*config.idle_timeout_seconds()),
+            pings_sent: 0,
+            last_ping: now,
+            awaiting_pong: None,
+            last_received: now,
+            last_check: (now, wall),
+        }
+    }
+
+    /// Record that something was received from the server, proving the connection is alive.
+    pub fn received(&mut self, now: Instant) {
+        self.last_received = now;
+    }
+
+    /// Record a pong from the server.
+    pub fn pong(&mut self, payload: &[u8]) {
+        if matches!(&self.awaiting_pong, Some((expected, _)) if expected == payload) {
+            self.awaiting_pong = None;
+        }
+    }
+
+    /// When [`Heartbeat::check`] should next be called.
+    pub fn next_check(&self) -> Instant {
+        let mut next = self.last_check.0 + CLOCK_CHECK_INTERVAL;
+        if let Some((_, deadline)) = &self.awaiting_pong {
+            next = next.min(*deadline);
+        } else if let Some(interval) = self.ping_interval {
+            next = next.min(self.last_ping + interval);
+        }
+        if let Some(idle) = self.idle_timeout {
+            next = next.min(self.last_received + idle);
+        }
+        next
+    }
+
+    /// Check the connection, returning what should be done about it.
+    pub fn check(&mut self, now: Instant, wall: SystemTime) -> Beat {
+        let (last_now, last_wall) = std::mem::replace(&mut self.last_check, (now, wall));
+
+        // the monotonic clock stops while the system is suspended but the wall clock doesn't, and if the
+        // monotonic clock has jumped too then we weren't scheduled for a long time (e.g. a paused VM)
+        let elapsed = now.saturating_duration_since(last_now);
+        let drift = match wall.duration_since(last_wall) {
+            Ok(wall_elapsed) if wall_elapsed > elapsed => wall_elapsed - elapsed,
+            Ok(wall_elapsed) => elapsed - wall_elapsed,
+            // the clock was set backwards
+            Err(e) => elapsed + e.duration(),
+        };
+        if drift > CLOCK_JUMP_THRESHOLD {
+            return Beat::Dead(format!(
+                "clock jumped by {}s, the system may have been suspended",
+                drift.as_secs()
+            ));
+        }
+        if elapsed > CLOCK_CHECK_INTERVAL + CLOCK_JUMP_THRESHOLD {
+            return Beat::Dead(format!(
+                "agent was not scheduled for {}s, the system may have been suspended",
+                elapsed.as_secs()
+            ));
+        }
+
+        if let Some((_, deadline)) = &self.awaiting_pong {
+            if now >= *deadline {
+                return Beat::Dead(format!(
+                    "no pong received within {}s",
+                    self.pong_timeout.as_secs()
+                ));
+            }
+        }
+
+        if let Some(idle) = self.idle_timeout {
+            if now.saturating_duration_since(self.last_received) >= idle {
+                return Beat::Dead(format!("nothing received for {}s", idle.as_secs()));
+            }
+        }
+
+        match self.ping_interval {
+            Some(interval) if self.awaiting_pong.is_none() && now >= self.last_ping + interval => {
+                self.pings_sent += 1;
+                let payload = self.pings_sent.to_be_bytes().to_vec();
+                self.last_ping = now;
+                self.awaiting_pong = Some((payload.clone(), now + self.pong_timeout));
+                Beat::Ping(payload)
+            }
+            _ => Beat::Idle,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use tokio::time::Instant;
+
+    use super::{Beat, Heartbeat, CLOCK_CHECK_INTERVAL};
+
+    fn heartbeat(ping: u64, pong: u64, idle: u64, now: Instant, wall: SystemTime) -> Heartbeat {
+        Heartbeat {
+            ping_interval: super::enabled(ping),
+            pong_timeout: Duration::from_secs(pong),
+            idle_timeout: super::enabled(idle),
+            pings_sent: 0,
+            last_ping: now,
+            awaiting_pong: None,
+            last_received: now,
+            last_check: (now, wall),
+        }
+    }
+
+    #[test]
+    fn test_ping_and_pong() {
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        let mut hb = heartbeat(10, 5, 0, now, wall);
+        assert_eq!(hb.next_check(), now + CLOCK_CHECK_INTERVAL);
+
+        let s = Duration::from_secs;
+        assert_eq!(hb.check(now + s(5), wall + s(5)), Beat::Idle);
+        let payload = match hb.check(now + s(10), wall + s(10)) {
+            Beat::Ping(payload) => payload,
+            beat => panic!("expected a ping, got {:?}", beat),
+        };
+        assert_eq!(hb.next_check(), now + s(15));
+
+        // a pong for some other ping doesn't count
+        hb.pong(b"other");
+        assert!(matches!(hb.check(now + s(15), wall + s(15)), Beat::Dead(_)));
+
+        hb.pong(&payload);
+        assert_eq!(hb.check(now + s(16), wall + s(16)), Beat::Idle);
+        assert!(matches!(
+            hb.check(now + s(20), wall + s(20)),
+            Beat::Ping(p) if p != payload
+        ));
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        let mut hb = heartbeat(0, 5, 8, now, wall);
+        let s = Duration::from_secs;
+
+        assert_eq!(hb.check(now + s(5), wall + s(5)), Beat::Idle);
+        hb.received(now + s(5));
+        assert_eq!(hb.next_check(), now + s(10));
+        assert_eq!(hb.check(now + s(10), wall + s(10)), Beat::Idle);
+        assert!(matches!(hb.check(now + s(13), wall + s(13)), Beat::Dead(_)));
+    }
+
+    #[test]
+    fn test_clock_jumps() {
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        let s = Duration::from_secs;
+
+        // suspended, the wall clock moved on without us
+        let mut hb = heartbeat(0, 5, 0, now, wall);
+        assert!(matches!(hb.check(now + s(5), wall + s(600)), Beat::Dead(_)));
+
+        // the clock was set backwards
+        let mut hb = heartbeat(0, 5, 0, now, wall);
+        assert!(matches!(hb.check(now + s(5), wall - s(60)), Beat::Dead(_)));
+
+        // not scheduled for a long time
+        let mut hb = heartbeat(0, 5, 0, now, wall);
+        assert!(matches!(
+            hb.check(now + s(600), wall + s(600)),
+            Beat::Dead(_)
+        ));
+
+        // small amounts of drift are fine
+        let mut hb = heartbeat(0, 5, 0, now, wall);
+        assert_eq!(hb.check(now + s(5), wall + s(6)), Beat::Idle);
+        assert_eq!(hb.check(now + s(10), wall + s(4)), Beat::Idle);
+    }
+}
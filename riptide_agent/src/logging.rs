@@ -0,0 +1,104 @@
+//! Logging for the agent, configured through `riptide.conf`.
+//!
+//! Logs are written to stdout as text or JSON, and optionally to a rotating file in the config directory.
+//! Records from the `log` crate are captured too, so they carry the context of the span they were emitted
+//! in (e.g. the websocket session, or the upload id and file id of an upload). `RUST_LOG` overrides the
+//! configured level when set.
+
+use log::warn;
+use riptide_config::Config;
+use tracing::Subscriber;
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    fmt::MakeWriter, layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+
+/// Name of the log files written to the log directory, suffixed with the date when rotated.
+const LOG_FILE_NAME: &str = "agent.log";
+
+/// Build a formatting layer writing to `writer`, in JSON or plain text.
+fn format_layer<S, W>(json: bool, ansi: bool, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    if json {
+        layer
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed()
+    } else {
+        layer.with_ansi(ansi).boxed()
+    }
+}
+
+/// Set up logging using the settings in `config`, or the defaults if there is no config yet.
+///
+/// When logging to a file, the returned guard must be kept alive until the agent exits, so that
+/// buffered lines are flushed.
+pub fn init(config: Option<&Config>) -> Option<WorkerGuard> {
+    let mut problems = Vec::new();
+
+    let level = config.map(|c| c.log_level().as_str()).unwrap_or("info");
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(level).unwrap_or_else(|e| {
+            problems.push(format!("Invalid log_level `{}`, using info: {}", level, e));
+            EnvFilter::new("info")
+        }),
+    };
+
+    let json = match config.map(|c| c.log_format().as_str()) {
+        None | Some("text") => false,
+        Some("json") => true,
+        Some(other) => {
+            problems.push(format!(
+                "Invalid log_format `{}`, expected text or json",
+                other
+            ));
+            false
+        }
+    };
+
+    let mut guard = None;
+    let file_layer = match config {
+        Some(config) if *config.log_file_enabled() => {
+            let rotation = match config.log_file_rotation().as_str() {
+                "hourly" => Rotation::HOURLY,
+                "daily" => Rotation::DAILY,
+                "never" => Rotation::NEVER,
+                other => {
+                    problems.push(format!(
+                        "Invalid log_file_rotation `{}`, expected hourly, daily or never",
+                        other
+                    ));
+                    Rotation::DAILY
+                }
+            };
+            let appender =
+                RollingFileAppender::new(rotation, Config::log_directory(), LOG_FILE_NAME);
+            let (writer, g) = tracing_appender::non_blocking(appender);
+            guard = Some(g);
+            Some(format_layer(json, false, writer))
+        }
+        _ => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(format_layer(json, true, std::io::stdout))
+        .with(file_layer)
+        .init();
+
+    for problem in problems {
+        warn!("{}", problem);
+    }
+
+    guard
+}
@@ -2,19 +2,33 @@
 //! This server application aims to handle incoming connections from the server agent.
 //! It needs to serve requested files when needed, and provide information back to the server.
 //! It has no direct connection to the user - but instead reads off a database that the cli
-//! tool also modifies. This largely isolates it, and prevents us from needing to write
-//! a communication layer between the the CLI tool and the server agent.
+//! tool also modifies. While running, it also listens on a local unix socket so that the cli can
+//! query its status, see active uploads, and request reloads, pauses and expiry sweeps. The cli
+//! falls back to the database alone when the agent isn't running.
 //! # Function
 //! 1. Connect to the Central-API on first start, and attempt to request an ID.
 //! 2. Recieve our ID, and store that in a config file.
 //! 3. Attempt to connect to the websocket endpoint of the Central-API, on failure repeat steps 1 and 2 again.
 //! 4. With a succesful websocket connection gracefully handle incoming requests from the Central-API, primarily:
 //!     - Requests for metadata/file status.
-//!     - File upload requests.
+//!     - File upload requests, reporting progress and the outcome of each upload back to the Central-API.
+//!       Shares with a download limit are removed once it has been reached, and one-time shares as soon as
+//!       they have been downloaded.
 //!     - Health requests.
 //!     - Closing websocket.
-//! 5. In the event that the Central-API is not available for a connection or disconnects us, sleep for 1 minute then
-//!    re-attempt the connection.
+//! 5. In the event that the Central-API is not available for a connection or disconnects us, back off exponentially
+//!    (up to `reconnect_delay_minutes`) then re-attempt the connection. If the Central-API closes the connection because
+//!    it is going away we reconnect quickly, and if it rejects the agent we stop until the agent is re-registered.
+//! 6. When the cli requests a reload, re-read the configuration in place. The websocket is only re-established
+//!    if the connection details have changed, any in-flight uploads are allowed to finish.
+//! 7. On SIGINT, SIGTERM or SIGQUIT, stop accepting uploads and let the server know we are going away. Running uploads
+//!    are given up to `shutdown_timeout_seconds` to finish before the websocket is closed.
+//! 8. Log as text or JSON at the configured `log_level`, optionally to a rotating file in the config directory.
+//!    Lines are tagged with the websocket session, and the upload id and file id they relate to.
+//! 9. When `metrics_enabled` is set, serve Prometheus metrics on `metrics_address` (localhost by default).
+//! 10. Ping the Central-API regularly, and reconnect if it stops answering, nothing is heard from it for
+//!     `idle_timeout_seconds`, or the system clock jumps (e.g. after resuming from sleep).
+//! 11. Keep a single connection to the database open for the life of the agent, rather than opening one per request.
 
 #![warn(
     missing_docs,
@@ -30,100 +44,459 @@
     deprecated
 )]
 
+mod control;
+mod database;
 mod error;
+mod heartbeat;
+mod logging;
+mod metrics;
+mod reconnect;
+mod scheduler;
+mod session;
+mod throttle;
+mod upload;
 
-use std::{sync::Arc, time::Duration};
+#[cfg(test)]
+mod mock_api;
+#[cfg(test)]
+mod tests;
 
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use database::Database;
 use error::AgentError;
 use futures::{SinkExt, StreamExt};
+use heartbeat::{Beat, Heartbeat};
 use log::{debug, error, info, trace, warn};
+use metrics::Metrics;
+use reconnect::ReconnectPolicy;
 use riptide_config::Config;
-use riptide_database::{establish_connection, get_share_by_id, Share};
-use tokio::{fs, net::TcpStream, sync::RwLock, time::Instant};
+use riptide_database::{get_share_by_id, Share, ShareKind};
+use scheduler::{Admission, Scheduler};
+use session::{Session, SessionOutcome};
+use throttle::Throttle;
+use tokio::{
+    fs,
+    net::TcpStream,
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, Notify, RwLock},
+    time::Instant,
+};
 use tokio_tungstenite::{
-    tungstenite::{protocol::WebSocketConfig, Message as TungsteniteMessage},
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig},
+        Error as TungsteniteError, Message as TungsteniteMessage,
+    },
     MaybeTlsStream, WebSocketStream,
 };
+use tokio_util::sync::CancellationToken;
+use tracing::{info_span, Instrument, Span};
+use upload::ActiveUploads;
 use ws_com_framework::{error::ErrorKind, Message};
 
-const MIN_RECONNECT_DELAY: usize = 5000;
+/// Delay before the first reconnection attempt, this doubles with each failure.
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
 
-/// Self contained function to upload files to the server
-async fn upload_file(metadata: Share, config: Arc<RwLock<Config>>, url: String) {
-    let loc = (*config.read().await.file_store_location()).join(metadata.file_id.to_string());
+/// A connection that lasts this long is considered stable, resetting the reconnection backoff.
+const STABLE_CONNECTION_DURATION: Duration = Duration::from_secs(60);
 
-    let mut a = 0;
-    loop {
-        let f = fs::File::open(&loc)
-            .await
-            .expect("File unexpectedly not available!");
-
-        let f = f.into_std().await;
-
-        let local_url = url.clone();
-        let res = tokio::task::spawn_blocking(move || {
-            ureq::post(&local_url)
-                .set("Content-Type", "application/octet-stream")
-                .send(f)
-        })
+/// Extra time given to the websocket to close after the shutdown timeout has passed.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// How long to wait for the websocket to close, a dead connection may never finish closing.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Channel used to send responses back to the server over the websocket.
+type Outgoing = mpsc::Sender<Result<Option<Message>, AgentError>>;
+
+/// Shared state of the agent, cloned into each task that needs it.
+#[derive(Debug, Clone)]
+struct Context {
+    config: Arc<RwLock<Config>>,
+    database: Arc<Database>,
+    session: Arc<RwLock<Session>>,
+    scheduler: Arc<Scheduler>,
+    throttle: Arc<Throttle>,
+    uploads: Arc<ActiveUploads>,
+    metrics: Arc<Metrics>,
+    reconnect: Arc<Notify>,
+    shutdown: CancellationToken,
+}
+
+impl Context {
+    /// Set up the state of a freshly started agent, using `config`.
+    fn new(config: Config) -> Context {
+        Context {
+            scheduler: Arc::new(Scheduler::new(
+                *config.max_concurrent_uploads(),
+                *config.max_concurrent_uploads_per_share(),
+            )),
+            throttle: Arc::new(Throttle::new(&config)),
+            database: Arc::new(Database::start(config.database_location().clone())),
+            config: Arc::new(RwLock::new(config)),
+            session: Arc::new(RwLock::new(Session::default())),
+            uploads: Arc::new(ActiveUploads::default()),
+            metrics: Arc::new(Metrics::default()),
+            reconnect: Arc::new(Notify::new()),
+            shutdown: CancellationToken::new(),
+        }
+    }
+}
+
+/// Record that a share's file can no longer be served, so that the cli can show it as broken.
+async fn mark_share_broken(ctx: &Context, file_id: i64, reason: String) {
+    warn!("Share {} is broken: {}", file_id, reason);
+    let res = ctx
+        .database
+        .run(move |conn| riptide_database::mark_share_broken(conn, file_id as u32, &reason))
         .await;
 
-        match res {
-            Ok(_) => break,
-            Err(e) => {
-                a += 1;
-                if a >= *config.read().await.max_upload_attempts() {
-                    error!("Failed to upload file to endpoint, error: {}", e);
-                    break;
-                }
+    if let Err(e) = res {
+        error!("Failed to mark share {} as broken: {}", file_id, e);
+    }
+}
+
+/// Check that the file (or directory of a live archive) backing a share can be served, marking the share as
+/// broken if not. A share which was broken is checked again, and no longer broken if its file has been restored.
+async fn check_share_file(ctx: &Context, share: &Share) -> Result<(), String> {
+    let res = match share.kind {
+        ShareKind::File => {
+            let loc = ctx
+                .config
+                .read()
+                .await
+                .file_store_location()
+                .join(share.file_id.to_string());
+            upload::verify_share_file(&loc, share.file_size as u64).await
+        }
+        ShareKind::LiveArchive => upload::verify_live_archive(share).await,
+    };
+    match res {
+        Err(reason) => {
+            if share.broken_reason.as_ref() != Some(&reason) {
+                mark_share_broken(ctx, share.file_id, reason.clone()).await;
+            }
+            Err(reason)
+        }
+        Ok(()) if share.broken_reason.is_some() => {
+            info!("Share {} has been restored", share.file_id);
+            let file_id = share.file_id;
+            let res = ctx
+                .database
+                .run(move |conn| riptide_database::clear_share_broken(conn, file_id as u32))
+                .await;
+            if let Err(e) = res {
+                error!("Failed to clear broken share {}: {}", file_id, e);
+            }
+            Ok(())
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+/// Delete the stored file of a share which has been removed from the database.
+async fn remove_share_file(config: &Arc<RwLock<Config>>, file_id: i64) -> std::io::Result<()> {
+    let path = config
+        .read()
+        .await
+        .file_store_location()
+        .join(file_id.to_string());
+    match fs::remove_file(path).await {
+        // the file of a broken share may already be gone
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+/// Count a completed download of a share, removing the share once it has no downloads left.
+async fn record_download(ctx: &Context, file_id: i64) {
+    let res = ctx
+        .database
+        .run(move |conn| riptide_database::record_download(conn, file_id as u32))
+        .await;
+
+    match res {
+        Ok(Some(share)) => {
+            info!(
+                "Share {} has reached its download limit, removing it",
+                share.file_id
+            );
+            if let Err(e) = remove_share_file(&ctx.config, share.file_id).await {
+                error!("Failed to remove file of share {}: {}", share.file_id, e);
             }
         }
+        Ok(None) => {}
+        Err(e) => error!("Failed to record download of share {}: {}", file_id, e),
+    }
+}
+
+/// Remove a one-time share and its file, now that it has been downloaded.
+async fn burn_share(ctx: &Context, file_id: i64) {
+    info!(
+        "One-time share {} has been downloaded, removing it",
+        file_id
+    );
+    let res = ctx
+        .database
+        .run(move |conn| riptide_database::remove_share(conn, file_id as u32))
+        .await;
+
+    if let Err(e) = res {
+        error!("Failed to remove one-time share {}: {}", file_id, e);
+    }
+
+    if let Err(e) = remove_share_file(&ctx.config, file_id).await {
+        error!("Failed to remove file of share {}: {}", file_id, e);
+    }
+}
+
+/// Build an unsolicited status message, used to keep the server informed about an upload or the agent.
+async fn status_update(ctx: &Context, upload_id: String, ready: bool, message: String) -> Message {
+    Message::StatusRes {
+        public_id: ctx.config.read().await.public_id().unwrap(),
+        ready,
+        uptime: ctx.session.read().await.uptime().as_secs(),
+        upload_id,
+        message: Some(message),
+    }
+}
+
+/// Build a status message about an upload. The state of the upload is given only in the message, while `ready`
+/// reports whether the agent can serve files, just as in reply to a status request.
+async fn upload_status(ctx: &Context, upload_id: String, message: String) -> Message {
+    let ready = check_ready(ctx).await.is_ok();
+    status_update(ctx, upload_id, ready, message).await
+}
+
+/// Check that the agent is able to serve files, returning the reason if it is not.
+async fn check_ready(ctx: &Context) -> Result<(), String> {
+    let file_store_location = ctx.config.read().await.file_store_location().clone();
+
+    match fs::metadata(&file_store_location).await {
+        Ok(m) if m.is_dir() => {}
+        Ok(_) => return Err(String::from("File store is not a directory")),
+        Err(e) => return Err(format!("File store is unavailable: {}", e)),
+    }
+
+    ctx.database
+        .run(|_| Ok(()))
+        .await
+        .map_err(|e| format!("Database is unavailable: {}", e))
+}
+
+/// Span giving context to every log line emitted while handling a message, so that lines about the same
+/// upload can be correlated.
+fn message_span(m: &Message) -> Span {
+    match m {
+        Message::UploadTo {
+            file_id,
+            upload_url,
+        } => info_span!(
+            "upload",
+            file_id = *file_id,
+            upload_id = %upload::upload_id_from_url(upload_url).unwrap_or_default()
+        ),
+        Message::MetadataReq { file_id, upload_id } => {
+            info_span!("metadata", file_id = *file_id, upload_id = %upload_id)
+        }
+        Message::StatusReq { upload_id, .. } => info_span!("status", upload_id = %upload_id),
+        _ => info_span!("message"),
     }
-    debug!("File {} uploaded to: {}", metadata.file_name, url);
 }
 
 async fn handle_message(
     m: Message,
-    config: Arc<RwLock<Config>>,
+    ctx: Context,
+    tx: Outgoing,
+    cancel: CancellationToken,
 ) -> Result<Option<Message>, AgentError> {
+    let Context {
+        config,
+        session,
+        scheduler,
+        throttle,
+        metrics,
+        ..
+    } = &ctx;
+    metrics.message(&m);
     match m {
         Message::UploadTo {
             file_id,
             upload_url,
         } => {
-            //XXX: use tokio_scoped to avoid the allocation here - or wrap config in an arc globally
-            let database_location = config.read().await.database_location().clone();
-            let item = tokio::task::spawn_blocking(move || {
-                match establish_connection(&database_location) {
-                    Ok(ref mut conn) => get_share_by_id(conn, &file_id),
-                    Err(e) => Err(e),
-                }
-            })
-            .await??;
-
-            if let Some(f) = item {
-                upload_file(f, config, upload_url).await;
-                Ok(None)
-            } else {
-                let upload_id = upload_url
-                    .split('/')
-                    .last()
-                    .expect("Upload URL is invalid, no / found!");
+            let upload_id = upload::upload_id_from_url(&upload_url).unwrap_or_default();
+            let file_doesnt_exist = |upload_id| {
                 Ok(Some(Message::Error {
                     kind: ErrorKind::FileDoesntExist,
-                    reason: Some(upload_id.to_string()),
+                    reason: Some(upload_id),
                 }))
+            };
+
+            if ctx.shutdown.is_cancelled() {
+                let message = String::from("Agent is shutting down, upload refused");
+                return Ok(Some(upload_status(&ctx, upload_id, message).await));
+            }
+
+            // reserved before the share is read, so that downloads being recorded are counted at least once
+            let reservation = scheduler.reserve_download(file_id as i64);
+            let item = ctx
+                .database
+                .run(move |conn| get_share_by_id(conn, &file_id))
+                .await?;
+
+            let f = match item {
+                Some(f) => f,
+                None => return file_doesnt_exist(upload_id),
+            };
+            if matches!(f.remaining_downloads(), Some(n) if reservation.in_flight() as i64 > n) {
+                debug!("Share {} has no downloads left", f.file_id);
+                return file_doesnt_exist(upload_id);
+            }
+
+            if let Err(reason) = check_share_file(&ctx, &f).await {
+                debug!("Unable to upload file {}: {}", f.file_id, reason);
+                return file_doesnt_exist(upload_id);
+            }
+
+            // only a range running to the end of the file completes a download, so one stopping short of it
+            // would let a one-time or limited share be downloaded over and over
+            let range = upload::ByteRange::from_url(&upload_url).ok().flatten();
+            let complete = !matches!(range, Some(r) if !r.reaches_end(f.file_size as u64));
+            if (f.one_time || f.max_downloads.is_some()) && !complete {
+                debug!("Refusing partial range of limited share {}", f.file_id);
+                let message = String::from(
+                    "Upload refused, a share with limited downloads can only be resumed up to the end of the file",
+                );
+                return Ok(Some(upload_status(&ctx, upload_id, message).await));
+            }
+
+            // a one-time share may only ever have a single upload in flight, so that it can't be replayed
+            let admission = if f.one_time {
+                match scheduler.admit_exclusive(f.file_id) {
+                    Some(admission) => admission,
+                    None => {
+                        debug!("One-time share {} is already being uploaded", f.file_id);
+                        return file_doesnt_exist(upload_id);
+                    }
+                }
+            } else {
+                scheduler.admit(f.file_id)
+            };
+
+            let _permit = match admission {
+                Admission::Ready(permit) => permit,
+                // shutdown started while the share was being checked
+                Admission::Closed => {
+                    let message = String::from("Agent is shutting down, upload refused");
+                    return Ok(Some(upload_status(&ctx, upload_id, message).await));
+                }
+                Admission::Queued(position, ticket) => {
+                    debug!(
+                        "Upload of file {} queued at position {}",
+                        f.file_id, position
+                    );
+                    let message = format!("Upload queued at position {}", position);
+                    let queued = upload_status(&ctx, upload_id.clone(), message).await;
+                    if tx.send(Ok(Some(queued))).await.is_err() {
+                        debug!("Websocket closed before queued status could be sent");
+                    }
+
+                    tokio::select! {
+                        permit = ticket.wait() => permit,
+                        _ = cancel.cancelled() => {
+                            debug!("Queued upload of file {} cancelled", f.file_id);
+                            return Ok(None);
+                        }
+                    }
+                }
+            };
+
+            // forward progress of the upload to the server as it happens
+            let entry = ctx.uploads.start(f.file_id, upload_id.clone());
+            let started = Instant::now();
+            let (events_tx, mut events_rx) = mpsc::channel(8);
+            let upload = upload::upload_file(
+                &f,
+                config.clone(),
+                &upload_url,
+                throttle.clone(),
+                cancel,
+                events_tx,
+            );
+            let forward = async {
+                while let Some(event) = events_rx.recv().await {
+                    entry.update(&event);
+                    if let upload::UploadEvent::Complete { sent, .. } = event {
+                        metrics.upload_complete(sent, started.elapsed());
+                    }
+                    let status = upload_status(&ctx, upload_id.clone(), event.to_string());
+                    if tx.send(Ok(Some(status.await))).await.is_err() {
+                        debug!("Websocket closed before upload progress could be sent");
+                    }
+                }
+            };
+            let (res, ()) = tokio::join!(upload, forward);
+
+            match &res {
+                Ok(()) | Err(AgentError::Cancelled) => {}
+                Err(e) => metrics.upload_failed(e),
+            }
+
+            match res {
+                Ok(()) if !complete => Ok(None),
+                Ok(()) if f.one_time => {
+                    burn_share(&ctx, f.file_id).await;
+                    Ok(None)
+                }
+                Ok(()) => {
+                    record_download(&ctx, f.file_id).await;
+                    Ok(None)
+                }
+                Err(AgentError::ReadFile(e)) => {
+                    mark_share_broken(&ctx, f.file_id, format!("file is unreadable: {}", e)).await;
+                    file_doesnt_exist(upload_id)
+                }
+                Err(AgentError::SourceChanged(reason)) => {
+                    mark_share_broken(&ctx, f.file_id, reason).await;
+                    file_doesnt_exist(upload_id)
+                }
+                Err(AgentError::Cancelled) => {
+                    debug!("Upload of file {} cancelled", f.file_id);
+                    Ok(None)
+                }
+                Err(e) => {
+                    error!("Failed to upload file {} to endpoint: {}", f.file_id, e);
+                    session
+                        .write()
+                        .await
+                        .error(format!("Failed to upload file {}: {}", f.file_id, e));
+                    Ok(None)
+                }
             }
         }
         Message::MetadataReq { file_id, upload_id } => {
-            let database_location = config.read().await.database_location().clone();
-            let item = tokio::task::spawn_blocking(move || {
-                match establish_connection(&database_location) {
-                    Ok(ref mut conn) => get_share_by_id(conn, &file_id),
-                    Err(e) => Err(e),
-                }
-            })
-            .await??;
+            let item = ctx
+                .database
+                .run(move |conn| get_share_by_id(conn, &file_id))
+                .await?;
+
+            let item = match item {
+                Some(f) if f.remaining_downloads() == Some(0) => None,
+                Some(f) => match check_share_file(&ctx, &f).await {
+                    Ok(()) => Some(f),
+                    Err(reason) => {
+                        debug!(
+                            "Unable to serve metadata for file {}: {}",
+                            f.file_id, reason
+                        );
+                        None
+                    }
+                },
+                None => None,
+            };
 
             if let Some(f) = item {
                 Ok(Some(Message::MetadataRes {
@@ -149,13 +522,38 @@ async fn handle_message(
         Message::StatusReq {
             public_id: _,
             upload_id,
-        } => Ok(Some(Message::StatusRes {
-            public_id: config.read().await.public_id().unwrap(),
-            ready: true,
-            uptime: 0, //TODO: record uptime, this should be time connected to the api - not the time the agent has been running
-            upload_id,
-            message: Some(String::from("Ready to upload")),
-        })),
+        } => {
+            let (ready, mut message) = match check_ready(&ctx).await {
+                Ok(()) => (true, String::from("Ready to upload")),
+                Err(e) => (false, e),
+            };
+
+            let session = session.read().await;
+            if session.reconnects() > 0 {
+                message = format!(
+                    "{} (reconnects: {}, last disconnect: {})",
+                    message,
+                    session.reconnects(),
+                    session.last_disconnect_reason().unwrap_or("unknown"),
+                );
+            }
+
+            let (running, queued) = scheduler.counts();
+            if running > 0 || queued > 0 {
+                message = format!(
+                    "{} (uploads running: {}, queued: {})",
+                    message, running, queued
+                );
+            }
+
+            Ok(Some(Message::StatusRes {
+                public_id: config.read().await.public_id().unwrap(),
+                ready,
+                uptime: session.uptime().as_secs(),
+                upload_id,
+                message: Some(message),
+            }))
+        }
 
         Message::Ok => Ok(None),
         Message::Error { kind, reason } => {
@@ -173,147 +571,236 @@ async fn handle_message(
     }
 }
 
+/// Serialize and send a message to the server.
+async fn send_to_server(
+    websocket: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+    msg: Message,
+) -> Result<(), AgentError> {
+    let bin: Vec<u8> = msg.try_into()?;
+    websocket.send(TungsteniteMessage::Binary(bin)).await?;
+    Ok(())
+}
+
+/// Serve a websocket session with the server until it ends, returning how it ended.
 async fn handle_ws(
-    config: Arc<RwLock<Config>>,
+    ctx: Context,
     websocket: WebSocketStream<MaybeTlsStream<TcpStream>>,
-) -> Result<bool, AgentError> {
+) -> SessionOutcome {
     let mut websocket = websocket;
 
-    let mut handles = Vec::new();
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<Result<Option<Message>, AgentError>>(20);
+    {
+        let mut session = ctx.session.write().await;
+        session.connected();
+        info!("Connected to server (reconnects: {})", session.reconnects());
+    }
+    ctx.metrics.connected();
 
-    let mut res = Ok(false);
-    loop {
+    let cancel = CancellationToken::new();
+    let (tx, mut rx) = mpsc::channel::<Result<Option<Message>, AgentError>>(20);
+
+    let mut heartbeat =
+        Heartbeat::new(&*ctx.config.read().await, Instant::now(), SystemTime::now());
+
+    // set once the server has sent a close frame, we keep reading until the close handshake completes
+    let mut closed_by_server: Option<SessionOutcome> = None;
+    // when shutting down, we keep serving the websocket until uploads finish or this deadline passes
+    let mut drain_deadline: Option<Instant> = None;
+    let outcome = loop {
         tokio::select! {
+                _ = ctx.shutdown.cancelled(), if drain_deadline.is_none() => {
+                    info!("Shutting down, waiting for running uploads to finish");
+                    // closed before waiting, so that every upload is either refused or waited on
+                    ctx.scheduler.close();
+                    let timeout = *ctx.config.read().await.shutdown_timeout_seconds();
+                    drain_deadline = Some(Instant::now() + Duration::from_secs(timeout));
+
+                    let message = String::from("Agent is shutting down");
+                    let going_away = status_update(&ctx, String::new(), false, message).await;
+                    if let Err(e) = send_to_server(&mut websocket, going_away).await {
+                        break SessionOutcome::Failed(e);
+                    }
+                },
+
+                _ = ctx.scheduler.wait_idle(), if drain_deadline.is_some() => {
+                    info!("All uploads finished, closing websocket");
+                    break SessionOutcome::Shutdown;
+                },
+
+                _ = tokio::time::sleep_until(drain_deadline.unwrap_or_else(Instant::now)), if drain_deadline.is_some() => {
+                    warn!("Timed out waiting for uploads to finish, cancelling remaining uploads");
+                    break SessionOutcome::Shutdown;
+                },
+
+                _ = tokio::time::sleep_until(heartbeat.next_check()) => {
+                    match heartbeat.check(Instant::now(), SystemTime::now()) {
+                        Beat::Idle => {}
+                        Beat::Ping(payload) => {
+                            trace!("Sending ping to server");
+                            if let Err(e) = websocket.send(TungsteniteMessage::Ping(payload)).await {
+                                break SessionOutcome::Failed(e.into());
+                            }
+                        }
+                        Beat::Dead(reason) => {
+                            warn!("Connection to server appears to be dead: {}", reason);
+                            break SessionOutcome::Failed(AgentError::ConnectionDead(reason));
+                        }
+                    }
+                },
+
+                _ = ctx.reconnect.notified() => {
+                    info!("Connection details changed, closing websocket to reconnect");
+                    break SessionOutcome::Reconfigured;
+                },
+
                 send_message = rx.recv() => {
                     debug!("Sending message to server");
                     trace!("Message: {:?}", send_message);
 
-                    if let Some(m) = send_message {
-                        match m {
-                            Ok(Some(msg)) => {
-                                let bin: Vec<u8> = match msg.try_into() {
-                                    Ok(d) => d,
-                                    Err(e) => {
-                                        res = Err(e.into());
-                                        break;
-                                    }
-                                };
-                                if let Err(e) = websocket
-                                    .send(TungsteniteMessage::Binary(bin))
-                                    .await
-                                {
-                                    res = Err(e.into());
-                                    break;
-                                }
-                            }
-                            Ok(None) => {}
-                            Err(e) => {
-                                res = Err(e);
-                                break;
+                    match send_message {
+                        Some(Ok(Some(msg))) => {
+                            if let Err(e) = send_to_server(&mut websocket, msg).await {
+                                break SessionOutcome::Failed(e);
                             }
                         }
-                    } else {
-                        error!("Message channel closed unexpectedly!");
-                        break;
+                        Some(Ok(None)) => {}
+                        Some(Err(e)) => break SessionOutcome::Failed(e),
+                        None => {
+                            error!("Message channel closed unexpectedly!");
+                            break SessionOutcome::Failed(AgentError::Other("message channel closed".into()));
+                        }
                     }
                 },
 
                 recv_message = websocket.next() => {
                     debug!("Received message from server");
                     trace!("Message: {:?}", recv_message);
+                    if let Some(Ok(_)) = recv_message {
+                        heartbeat.received(Instant::now());
+                    }
 
                 match recv_message {
                     Some(Ok(TungsteniteMessage::Binary(msg))) => {
                         let msg: Message = match msg.try_into() {
                             Ok(m) => m,
-                            Err(e) => {
-                                res = Err(e.into());
-                                break;
-                            }
+                            Err(e) => break SessionOutcome::Failed(e.into()),
                         };
 
                         let local_tx = tx.clone();
-                        let local_config = config.clone();
-                        let h = tokio::spawn(async move {
-                            local_tx
-                                .send(handle_message(msg, local_config).await)
-                                .await
-                                .unwrap();
+                        let local_ctx = ctx.clone();
+                        let local_cancel = cancel.child_token();
+                        let span = message_span(&msg);
+                        tokio::spawn(async move {
+                            let response =
+                                handle_message(msg, local_ctx, local_tx.clone(), local_cancel)
+                                    .instrument(span)
+                                    .await;
+                            if local_tx.send(response).await.is_err() {
+                                debug!("Websocket closed before response could be sent");
+                            }
                         });
-                        handles.push(h);
                     }
                     Some(Ok(TungsteniteMessage::Ping(msg))) => {
                         if let Err(e) = websocket
                             .send(TungsteniteMessage::Pong(msg))
                             .await
                         {
-                            res = Err(e.into());
-                            break;
+                            break SessionOutcome::Failed(e.into());
                         }
                     }
-                    Some(Ok(TungsteniteMessage::Pong(_))) => {
-                        info!("Pong recieved");
+                    Some(Ok(TungsteniteMessage::Pong(payload))) => {
+                        trace!("Pong recieved");
+                        heartbeat.pong(&payload);
                     }
                     Some(Ok(TungsteniteMessage::Text(msg))) => {
                         warn!("recieved text message from server: {}", msg)
                     }
-                    Some(Ok(TungsteniteMessage::Close(e))) => {
-                        info!("got close message from server message: {:?}", e);
-                        res = Ok(false); //XXX: should we try to reconnect?
+                    Some(Ok(TungsteniteMessage::Close(frame))) => {
+                        info!("Server closed the connection: {:?}", frame);
+                        closed_by_server = Some(SessionOutcome::from_close(frame.as_ref()));
                     }
                     Some(Ok(TungsteniteMessage::Frame(_))) => {
                         error!("recieved raw frame");
-                        res = Err(AgentError::BadFrame(String::from("got raw frame")));
-                        break;
+                        break SessionOutcome::Failed(AgentError::BadFrame(String::from("got raw frame")));
                     }
-                    Some(Err(e)) => {
-                        res = Err(e.into());
-                        break;
+                    Some(Err(e)) => break closed_by_server.unwrap_or_else(|| SessionOutcome::Failed(e.into())),
+                    None => {
+                        break closed_by_server.unwrap_or_else(|| {
+                            SessionOutcome::Closed(String::from("connection lost without a close frame"))
+                        })
                     }
-                    None => break,
                 }
             }
         }
-    }
+    };
 
-    // kill all the handles
-    for h in handles {
-        h.abort();
+    // cancel any running uploads, the tasks will wind down on their own. When reconnecting after a config
+    // reload, let running uploads finish rather than cancelling them
+    if !matches!(outcome, SessionOutcome::Reconfigured) {
+        cancel.cancel();
     }
 
-    websocket.close(None).await?;
-    res
+    ctx.metrics.disconnected();
+    ctx.session.write().await.disconnected(outcome.to_string());
+
+    let close_frame = match &outcome {
+        SessionOutcome::Shutdown => Some(CloseFrame {
+            code: CloseCode::Away,
+            reason: "agent shutting down".into(),
+        }),
+        // there's nothing left to close if the server closed the connection, or it has already failed
+        SessionOutcome::Closed(_)
+        | SessionOutcome::GoingAway(_)
+        | SessionOutcome::Rejected(_)
+        | SessionOutcome::Failed(AgentError::TokioError(_))
+        | SessionOutcome::Failed(AgentError::ConnectionDead(_)) => return outcome,
+        _ => None,
+    };
+    match tokio::time::timeout(CLOSE_TIMEOUT, websocket.close(close_frame)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => debug!("Failed to close websocket: {}", e),
+        Err(_) => warn!("Timed out closing websocket"),
+    }
+    outcome
 }
 
-/// Remove expired shares from the database
+/// Remove expired shares from the database, returning how many were removed
 async fn remove_expired_shares(
-    config: Arc<RwLock<Config>>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let database_location = config.read().await.database_location().clone();
-    let shares: Vec<Share> = tokio::task::spawn_blocking(move || {
-        let mut conn = establish_connection(&database_location)?;
-        riptide_database::remove_expired_shares(&mut conn)
-    })
-    .await??;
-
-    for share in shares {
-        let path = (*config.read().await.file_store_location()).join(share.file_id.to_string());
-        tokio::fs::remove_file(path).await?;
+    ctx: &Context,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let shares: Vec<Share> = ctx
+        .database
+        .run(riptide_database::remove_expired_shares)
+        .await?;
+
+    for share in &shares {
+        remove_share_file(&ctx.config, share.file_id).await?;
     }
+    ctx.metrics.expired_shares_removed(shares.len());
 
-    Ok(())
+    Ok(shares.len())
 }
 
-async fn run(config: Arc<RwLock<Config>>) {
-    let reader = config.read().await;
-    let ip = format!(
-        "{}/api/v1/ws/{}",
-        reader.websocket_address(),
-        reader.public_id().unwrap()
+async fn run(ctx: Context) {
+    let mut policy = ReconnectPolicy::new(
+        INITIAL_RECONNECT_DELAY,
+        INITIAL_RECONNECT_DELAY,
+        STABLE_CONNECTION_DURATION,
     );
-    let reconnect_delay = reader.reconnect_delay_minutes();
-    loop {
+
+    while !ctx.shutdown.is_cancelled() {
+        // re-read on every attempt, the config may have been reloaded since the last connection
+        let ip = {
+            let reader = ctx.config.read().await;
+            policy.set_max(Duration::from_secs(reader.reconnect_delay_minutes() * 60));
+            format!(
+                "{}/api/v1/ws/{}",
+                reader.websocket_address(),
+                reader.public_id().unwrap()
+            )
+        };
+
+        // if we couldn't reach the server at all, retry as soon as it becomes reachable again
+        let mut network_down = false;
         match tokio_tungstenite::connect_async_tls_with_config(
             &ip,
             Some(WebSocketConfig {
@@ -327,26 +814,144 @@ async fn run(config: Arc<RwLock<Config>>) {
         .await
         {
             Ok((t, _r)) => {
-                if let Err(e) = handle_ws(config.clone(), t).await {
-                    error!("error occurred when handling websocket: {}", e);
+                let connected_at = Instant::now();
+                let span = info_span!(
+                    "session",
+                    session = ctx.session.read().await.connections() + 1
+                );
+                let outcome = handle_ws(ctx.clone(), t).instrument(span).await;
+                policy.connection_ended(connected_at.elapsed());
+                if ctx.shutdown.is_cancelled() {
+                    if let SessionOutcome::Failed(e) = outcome {
+                        error!("error occurred when closing websocket: {}", e);
+                    }
+                    return;
+                }
+                match outcome {
+                    SessionOutcome::Shutdown => return,
+                    SessionOutcome::Reconfigured => {
+                        policy.reset();
+                        continue;
+                    }
+                    SessionOutcome::Closed(reason) => {
+                        info!("Connection closed: {}", reason);
+                    }
+                    SessionOutcome::GoingAway(reason) => {
+                        info!("Server is going away, reconnecting shortly: {}", reason);
+                        policy.reset();
+                    }
+                    SessionOutcome::Rejected(reason) => {
+                        error!(
+                            "Server rejected the agent, not reconnecting until it is re-registered: {}",
+                            reason
+                        );
+                        ctx.session.write().await.rejected(reason);
+                        tokio::select! {
+                            _ = ctx.reconnect.notified() => {
+                                info!("Connection details changed, reconnecting");
+                                policy.reset();
+                                continue;
+                            }
+                            _ = ctx.shutdown.cancelled() => return,
+                        }
+                    }
+                    SessionOutcome::Failed(e) => {
+                        network_down = matches!(e, AgentError::TokioError(TungsteniteError::Io(_)));
+                        error!("error occurred when handling websocket: {}", e);
+                        ctx.session.write().await.error(e.to_string());
+                    }
                 }
             }
             Err(e) => {
+                network_down = matches!(e, TungsteniteError::Io(_));
                 error!("Failed to connect to webserver {:?}", e);
+                ctx.session
+                    .write()
+                    .await
+                    .error(format!("Failed to connect to server: {}", e));
             }
         };
 
-        tokio::time::sleep(std::time::Duration::from_secs(std::cmp::max(
-            reconnect_delay * 60,
-            MIN_RECONNECT_DELAY as u64,
-        )))
+        let delay = policy.next_delay();
+        info!("Reconnecting in {} seconds", delay.as_secs());
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = ctx.reconnect.notified() => {
+                info!("Connection details changed, reconnecting immediately");
+                policy.reset();
+            }
+            _ = reconnect::wait_for_network(&ip), if network_down => {
+                info!("Network is reachable again, reconnecting immediately");
+            }
+            _ = ctx.shutdown.cancelled() => {}
+        }
+    }
+}
+
+/// Returns true if the new configuration requires the websocket to be re-established.
+fn requires_reconnect(old: &Config, new: &Config) -> bool {
+    old.websocket_address() != new.websocket_address()
+        || old.public_id() != new.public_id()
+        || old.private_key() != new.private_key()
+}
+
+/// Swap in a reloaded configuration, re-establishing the websocket if the connection details have changed.
+/// Everything else takes effect in place, without interrupting the connection or uploads in progress.
+async fn apply_config(ctx: &Context, new_config: Config) -> Result<(), AgentError> {
+    if new_config.public_id().is_none() {
+        return Err(AgentError::Other(
+            "reloaded configuration is not registered, keeping current configuration".into(),
+        ));
+    }
+
+    ctx.throttle.update(&new_config);
+    ctx.scheduler.set_limits(
+        *new_config.max_concurrent_uploads(),
+        *new_config.max_concurrent_uploads_per_share(),
+    );
+    ctx.database
+        .set_location(new_config.database_location().clone())
         .await;
+
+    let mut writer = ctx.config.write().await;
+    let reconnect = requires_reconnect(&writer, &new_config);
+    *writer = new_config;
+    drop(writer);
+
+    if reconnect {
+        info!("Connection details changed, re-establishing websocket");
+        ctx.reconnect.notify_one();
+    } else {
+        debug!("Configuration reloaded, connection unchanged");
+    }
+    Ok(())
+}
+
+/// Reload the configuration from disk as requested by the cli.
+async fn handle_reload(ctx: &Context) -> Result<(), AgentError> {
+    info!("Reload requested, reloading configuration");
+    let new_config: Config = tokio::task::spawn_blocking(Config::load_config)
+        .await?
+        .map_err(|e| AgentError::Other(Box::new(e)))?;
+    apply_config(ctx, new_config).await
+}
+
+/// Resolves when the agent is asked to stop, on SIGINT, SIGTERM (sent by systemd) or SIGQUIT.
+async fn shutdown_signal() -> &'static str {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    let mut quit = signal(SignalKind::quit()).expect("Failed to listen for SIGQUIT");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+        _ = quit.recv() => "SIGQUIT",
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    pretty_env_logger::init();
+    let initial_config = tokio::task::spawn_blocking(Config::load_config).await?.ok();
+    let _log_guard = logging::init(initial_config.as_ref());
 
     debug!("Validating config...");
     while !Config::exists() {
@@ -362,14 +967,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     debug!("Starting...");
     let config: Config = tokio::task::spawn_blocking(Config::load_config).await??;
-    let config = Arc::new(RwLock::new(config));
+    let ctx = Context::new(config);
 
     // spawn monitoring task to remove expired shares
-    let monitor_config = config.clone();
+    let monitor_ctx = ctx.clone();
     let monitor_handle = tokio::task::spawn(async move {
         loop {
             tokio::time::sleep(Duration::from_secs(60)).await;
-            if let Err(e) = remove_expired_shares(monitor_config.clone()).await {
+            if let Err(e) = remove_expired_shares(&monitor_ctx).await {
                 error!("Failed to remove expired shares: {}", e);
             }
         }
@@ -377,7 +982,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let reload_timer = tokio::time::sleep(Duration::from_secs(5));
 
-    let runner = run(config);
+    let control_listener = control::listen().await;
+    let control_handle =
+        control_listener.map(|listener| tokio::task::spawn(control::serve(ctx.clone(), listener)));
+    let metrics_handle = tokio::task::spawn(metrics::serve(ctx.clone()));
+
+    let runner = run(ctx.clone());
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
     tokio::pin!(monitor_handle);
     tokio::pin!(runner);
     tokio::pin!(reload_timer);
@@ -386,16 +998,24 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         tokio::select! {
             biased;
 
-            _ = tokio::signal::ctrl_c() => {
-                info!("SIGINT recieved, shutting down");
+            signal = &mut shutdown => {
+                info!("{} recieved, shutting down", signal);
+                ctx.shutdown.cancel();
+
+                // give running uploads a chance to finish, and the websocket a chance to close cleanly
+                let timeout = Duration::from_secs(*ctx.config.read().await.shutdown_timeout_seconds());
+                if tokio::time::timeout(timeout + SHUTDOWN_GRACE_PERIOD, &mut runner).await.is_err() {
+                    warn!("Timed out waiting for the connection to close");
+                }
                 break;
             }
 
             _ = &mut reload_timer => {
                 match Config::reload_requested() {
                     Ok(true) => {
-                        info!("Reload requested, shutting down");
-                        break; //HACK: instead of breaking, we should just reload the config properly
+                        if let Err(e) = handle_reload(&ctx).await {
+                            error!("Failed to reload configuration: {}", e);
+                        }
                     },
                     Ok(_) => {},
                     Err(e) => {
@@ -417,6 +1037,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
+    metrics_handle.abort();
+    if let Some(handle) = control_handle {
+        handle.abort();
+        control::remove_socket().await;
+    }
+
     debug!("Connection closed, Server Agent exiting....");
     Ok(())
 }
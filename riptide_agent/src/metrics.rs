@@ -0,0 +1,365 @@
+//! Prometheus metrics for the agent, optionally served over HTTP on localhost.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use log::{debug, error, info, warn};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use ws_com_framework::Message;
+
+use crate::{error::AgentError, Context};
+
+/// Upper bounds of the upload size histogram buckets, in bytes.
+const UPLOAD_BYTES_BUCKETS: [f64; 8] = [1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9, 1e10];
+
+/// Upper bounds of the upload duration histogram buckets, in seconds.
+const UPLOAD_SECONDS_BUCKETS: [f64; 10] =
+    [0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
+
+/// The largest request we will read from a scraper, anything bigger is not a metrics request.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// How long a scraper has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A cumulative histogram with fixed buckets.
+#[derive(Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    state: Mutex<HistogramState>,
+}
+
+#[derive(Debug, Default)]
+struct HistogramState {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            state: Mutex::new(HistogramState {
+                counts: vec![0; bounds.len()],
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        let mut state = self.state.lock().unwrap();
+        for (bound, count) in self.bounds.iter().zip(state.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        state.sum += value;
+        state.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let state = self.state.lock().unwrap();
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, count) in self.bounds.iter().zip(state.counts.iter()) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, state.count);
+        let _ = writeln!(out, "{}_sum {}", name, state.sum);
+        let _ = writeln!(out, "{}_count {}", name, state.count);
+    }
+}
+
+/// Metrics collected while the agent runs.
+#[derive(Debug)]
+pub struct Metrics {
+    connects: AtomicU64,
+    disconnects: AtomicU64,
+    messages: Mutex<BTreeMap<&'static str, u64>>,
+    upload_bytes: Histogram,
+    upload_seconds: Histogram,
+    upload_failures: Mutex<BTreeMap<&'static str, u64>>,
+    expired_shares_removed: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            connects: AtomicU64::new(0),
+            disconnects: AtomicU64::new(0),
+            messages: Mutex::new(BTreeMap::new()),
+            upload_bytes: Histogram::new(&UPLOAD_BYTES_BUCKETS),
+            upload_seconds: Histogram::new(&UPLOAD_SECONDS_BUCKETS),
+            upload_failures: Mutex::new(BTreeMap::new()),
+            expired_shares_removed: AtomicU64::new(0),
+        }
+    }
+}
+
+/// The name of a message variant, used as a metric label.
+fn message_kind(message: &Message) -> &'static str {
+    match message {
+        Message::Ok => "ok",
+        Message::Error { .. } => "error",
+        Message::UploadTo { .. } => "upload_to",
+        Message::MetadataReq { .. } => "metadata_req",
+        Message::MetadataRes { .. } => "metadata_res",
+        Message::AuthReq { .. } => "auth_req",
+        Message::AuthRes { .. } => "auth_res",
+        Message::StatusReq { .. } => "status_req",
+        Message::StatusRes { .. } => "status_res",
+        #[allow(unreachable_patterns)]
+        _ => "other",
+    }
+}
+
+impl Metrics {
+    /// Record that the websocket connected.
+    pub fn connected(&self) {
+        self.connects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that the websocket disconnected.
+    pub fn disconnected(&self) {
+        self.disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a message received from the server.
+    pub fn message(&self, message: &Message) {
+        *self
+            .messages
+            .lock()
+            .unwrap()
+            .entry(message_kind(message))
+            .or_insert(0) += 1;
+    }
+
+    /// Record a successful upload.
+    pub fn upload_complete(&self, bytes: u64, duration: Duration) {
+        self.upload_bytes.observe(bytes as f64);
+        self.upload_seconds.observe(duration.as_secs_f64());
+    }
+
+    /// Record a failed upload.
+    pub fn upload_failed(&self, error: &AgentError) {
+        *self
+            .upload_failures
+            .lock()
+            .unwrap()
+            .entry(error.kind())
+            .or_insert(0) += 1;
+    }
+
+    /// Record shares removed after expiring.
+    pub fn expired_shares_removed(&self, count: usize) {
+        self.expired_shares_removed
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Render the metrics in the Prometheus text format.
+    fn render(&self, file_store_bytes: Option<u64>) -> String {
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        counter(
+            "riptide_websocket_connects_total",
+            "Websocket connections established to the server.",
+            self.connects.load(Ordering::Relaxed),
+        );
+        counter(
+            "riptide_websocket_disconnects_total",
+            "Websocket connections to the server which have ended.",
+            self.disconnects.load(Ordering::Relaxed),
+        );
+        counter(
+            "riptide_expired_shares_removed_total",
+            "Shares removed after expiring.",
+            self.expired_shares_removed.load(Ordering::Relaxed),
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP riptide_messages_total Messages received from the server, by variant."
+        );
+        let _ = writeln!(out, "# TYPE riptide_messages_total counter");
+        for (kind, count) in self.messages.lock().unwrap().iter() {
+            let _ = writeln!(out, "riptide_messages_total{{kind=\"{}\"}} {}", kind, count);
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP riptide_upload_failures_total Uploads which failed, by reason."
+        );
+        let _ = writeln!(out, "# TYPE riptide_upload_failures_total counter");
+        for (reason, count) in self.upload_failures.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "riptide_upload_failures_total{{reason=\"{}\"}} {}",
+                reason, count
+            );
+        }
+
+        self.upload_bytes.render(
+            &mut out,
+            "riptide_upload_bytes",
+            "Size of successful uploads in bytes.",
+        );
+        self.upload_seconds.render(
+            &mut out,
+            "riptide_upload_duration_seconds",
+            "Time taken by successful uploads.",
+        );
+
+        if let Some(bytes) = file_store_bytes {
+            let _ = writeln!(
+                out,
+                "# HELP riptide_file_store_bytes Total size of the files in the file store."
+            );
+            let _ = writeln!(out, "# TYPE riptide_file_store_bytes gauge");
+            let _ = writeln!(out, "riptide_file_store_bytes {}", bytes);
+        }
+
+        out
+    }
+}
+
+/// Total size of the files in the file store.
+async fn file_store_size(location: &Path) -> std::io::Result<u64> {
+    let mut total = 0;
+    let mut entries = fs::read_dir(location).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if metadata.is_file() {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+/// Read the request line of a scrape, e.g. `GET /metrics HTTP/1.1`.
+async fn read_request_line(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < MAX_REQUEST_SIZE {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&buf);
+    Ok(request.lines().next().unwrap_or_default().to_string())
+}
+
+/// Answer a single HTTP request, only `GET /metrics` is supported.
+async fn handle_connection(ctx: &Context, mut stream: TcpStream) -> std::io::Result<()> {
+    let request_line = tokio::time::timeout(REQUEST_TIMEOUT, read_request_line(&mut stream))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "request timed out"))??;
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let location = ctx.config.read().await.file_store_location().clone();
+            let size = match file_store_size(&location).await {
+                Ok(size) => Some(size),
+                Err(e) => {
+                    warn!("Unable to measure file store for metrics: {}", e);
+                    None
+                }
+            };
+            ("200 OK", ctx.metrics.render(size))
+        }
+        _ => ("404 Not Found", String::from("Not Found\n")),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Serve metrics over HTTP if enabled in the config, runs until the agent exits.
+pub async fn serve(ctx: Context) {
+    let address = {
+        let config = ctx.config.read().await;
+        if !*config.metrics_enabled() {
+            debug!("Metrics endpoint disabled");
+            return;
+        }
+        config.metrics_address().clone()
+    };
+
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Unable to serve metrics on {}: {}", address, e);
+            return;
+        }
+    };
+    info!("Serving metrics on http://{}/metrics", address);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let ctx = ctx.clone();
+                tokio::task::spawn(async move {
+                    if let Err(e) = handle_connection(&ctx, stream).await {
+                        debug!("Metrics request failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept metrics connection: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Metrics;
+    use crate::error::AgentError;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.connected();
+        metrics.message(&ws_com_framework::Message::Ok);
+        metrics.message(&ws_com_framework::Message::Ok);
+        metrics.upload_complete(5_000, Duration::from_secs(2));
+        metrics.upload_failed(&AgentError::UploadStalled);
+        metrics.expired_shares_removed(3);
+
+        let out = metrics.render(Some(1024));
+        assert!(out.contains("riptide_websocket_connects_total 1\n"));
+        assert!(out.contains("riptide_messages_total{kind=\"ok\"} 2\n"));
+        assert!(out.contains("riptide_upload_bytes_bucket{le=\"1000\"} 0\n"));
+        assert!(out.contains("riptide_upload_bytes_bucket{le=\"10000\"} 1\n"));
+        assert!(out.contains("riptide_upload_duration_seconds_count 1\n"));
+        assert!(out.contains("riptide_upload_failures_total{reason=\"stalled\"} 1\n"));
+        assert!(out.contains("riptide_expired_shares_removed_total 3\n"));
+        assert!(out.contains("riptide_file_store_bytes 1024\n"));
+    }
+}
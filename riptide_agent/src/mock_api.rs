@@ -0,0 +1,258 @@
+//! An in-process stand-in for the Central-API, used to test the agent end to end.
+//!
+//! The fake serves the agent websocket at `/api/v1/ws/<public_id>` and accepts uploads at `/upload/<upload_id>`.
+//! It doesn't act on its own, tests accept each connection the agent makes and then play the part of the
+//! server by hand, sending requests and checking the replies.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::{SinkExt, StreamExt};
+use tokio::{sync::mpsc, task::JoinHandle};
+use warp::{
+    http::StatusCode,
+    ws::{Message as WsMessage, WebSocket, Ws},
+    Filter,
+};
+use ws_com_framework::Message;
+
+/// How long to wait on the agent before failing a test.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something received from the agent over a websocket.
+#[derive(Debug)]
+pub enum Event {
+    /// A protocol message.
+    Message(Message),
+    /// The agent closed the websocket, with the code and reason if it sent a close frame.
+    Closed(Option<(u16, String)>),
+}
+
+/// A file uploaded by the agent.
+#[derive(Debug, Clone)]
+pub struct ReceivedUpload {
+    pub upload_id: String,
+    pub content_range: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// Instructions from a test to the task serving its connection.
+#[derive(Debug)]
+enum Command {
+    Send(WsMessage),
+    Freeze,
+}
+
+/// A websocket connection from the agent.
+#[derive(Debug)]
+pub struct MockConnection {
+    pub public_id: u64,
+    outgoing: mpsc::UnboundedSender<Command>,
+    incoming: mpsc::UnboundedReceiver<Event>,
+}
+
+impl MockConnection {
+    /// Send a message to the agent.
+    pub fn send(&self, message: Message) {
+        let data: Vec<u8> = message.try_into().expect("failed to encode message");
+        self.outgoing
+            .send(Command::Send(WsMessage::binary(data)))
+            .expect("connection has closed");
+    }
+
+    /// Wait for the next thing the agent sends.
+    pub async fn recv(&mut self) -> Event {
+        tokio::time::timeout(TIMEOUT, self.incoming.recv())
+            .await
+            .expect("timed out waiting on the agent")
+            .unwrap_or(Event::Closed(None))
+    }
+
+    /// Wait for the next message from the agent that matches `f`, skipping any others (e.g. status updates).
+    pub async fn recv_matching<F>(&mut self, f: F) -> Message
+    where
+        F: Fn(&Message) -> bool,
+    {
+        loop {
+            match self.recv().await {
+                Event::Message(m) if f(&m) => return m,
+                Event::Message(_) => {}
+                Event::Closed(frame) => panic!("agent closed the connection: {:?}", frame),
+            }
+        }
+    }
+
+    /// Close the websocket cleanly, as the server would.
+    pub fn close(&self, code: u16, reason: &'static str) {
+        // the agent may have already gone away, in which case there's nothing to close
+        let _ = self
+            .outgoing
+            .send(Command::Send(WsMessage::close_with(code, reason)));
+    }
+
+    /// Stop responding while keeping the connection open, as if the network had silently gone away.
+    pub fn freeze(&self) {
+        let _ = self.outgoing.send(Command::Freeze);
+    }
+}
+
+/// The fake Central-API, shut down when dropped.
+#[derive(Debug)]
+pub struct MockApi {
+    addr: SocketAddr,
+    connections: mpsc::UnboundedReceiver<MockConnection>,
+    uploads: mpsc::UnboundedReceiver<ReceivedUpload>,
+    upload_status: Arc<AtomicU16>,
+    server: JoinHandle<()>,
+}
+
+/// Shuttle messages between the websocket and the test's [`MockConnection`] until either side goes away.
+async fn serve_connection(
+    websocket: WebSocket,
+    mut outgoing: mpsc::UnboundedReceiver<Command>,
+    incoming: mpsc::UnboundedSender<Event>,
+) {
+    let (mut sink, mut stream) = websocket.split();
+    loop {
+        tokio::select! {
+            m = outgoing.recv() => match m {
+                Some(Command::Send(m)) => {
+                    if sink.send(m).await.is_err() {
+                        break;
+                    }
+                }
+                // stop reading, so nothing is answered, until the test drops the connection
+                Some(Command::Freeze) => {
+                    while outgoing.recv().await.is_some() {}
+                    return;
+                }
+                // the test dropped the connection, hang up without a close frame
+                None => return,
+            },
+            m = stream.next() => match m {
+                Some(Ok(m)) if m.is_binary() => {
+                    let message = Message::try_from(m.into_bytes()).expect("agent sent an invalid message");
+                    let _ = incoming.send(Event::Message(message));
+                }
+                Some(Ok(m)) if m.is_close() => {
+                    let frame = m.close_frame().map(|(code, reason)| (code, reason.to_string()));
+                    let _ = incoming.send(Event::Closed(frame));
+                    return;
+                }
+                Some(Ok(_)) => {}
+                Some(Err(_)) | None => break,
+            },
+        }
+    }
+    let _ = incoming.send(Event::Closed(None));
+}
+
+impl MockApi {
+    /// Start the fake on an ephemeral port on localhost.
+    pub async fn start() -> MockApi {
+        let (connections_tx, connections) = mpsc::unbounded_channel();
+        let (uploads_tx, uploads) = mpsc::unbounded_channel();
+        let upload_status = Arc::new(AtomicU16::new(StatusCode::OK.as_u16()));
+
+        let websocket = warp::path!("api" / "v1" / "ws" / u64).and(warp::ws()).map(
+            move |public_id: u64, ws: Ws| {
+                let connections_tx = connections_tx.clone();
+                ws.on_upgrade(move |websocket| async move {
+                    let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
+                    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+                    let connection = MockConnection {
+                        public_id,
+                        outgoing: outgoing_tx,
+                        incoming: incoming_rx,
+                    };
+                    if connections_tx.send(connection).is_ok() {
+                        serve_connection(websocket, outgoing_rx, incoming_tx).await;
+                    }
+                })
+            },
+        );
+
+        let status = upload_status.clone();
+        let upload = warp::post()
+            .and(warp::path!("upload" / String))
+            .and(warp::header::optional::<String>("content-range"))
+            .and(warp::body::bytes())
+            .map(
+                move |upload_id, content_range, body: warp::hyper::body::Bytes| {
+                    let _ = uploads_tx.send(ReceivedUpload {
+                        upload_id,
+                        content_range,
+                        body: body.to_vec(),
+                    });
+                    let status = StatusCode::from_u16(status.load(Ordering::Relaxed)).unwrap();
+                    warp::reply::with_status(warp::reply(), status)
+                },
+            );
+
+        let (addr, server) = warp::serve(websocket.or(upload)).bind_ephemeral(([127, 0, 0, 1], 0));
+        MockApi {
+            addr,
+            connections,
+            uploads,
+            upload_status,
+            server: tokio::task::spawn(server),
+        }
+    }
+
+    /// The address the agent should connect its websocket to.
+    pub fn websocket_address(&self) -> String {
+        format!("ws://{}", self.addr)
+    }
+
+    /// The url the agent should upload to, for an upload with the given id.
+    pub fn upload_url(&self, upload_id: &str) -> String {
+        format!("http://{}/upload/{}", self.addr, upload_id)
+    }
+
+    /// Set the status returned for any further uploads.
+    pub fn set_upload_status(&self, status: StatusCode) {
+        self.upload_status.store(status.as_u16(), Ordering::Relaxed);
+    }
+
+    /// Wait for the agent to connect.
+    pub async fn accept(&mut self) -> MockConnection {
+        tokio::time::timeout(TIMEOUT, self.connections.recv())
+            .await
+            .expect("timed out waiting for the agent to connect")
+            .expect("server has stopped")
+    }
+
+    /// Check the agent doesn't connect within `duration`.
+    pub async fn expect_no_connection(&mut self, duration: Duration) {
+        if let Ok(conn) = tokio::time::timeout(duration, self.connections.recv()).await {
+            panic!("agent connected unexpectedly: {:?}", conn);
+        }
+    }
+
+    /// Check the agent doesn't upload a file within `duration`.
+    pub async fn expect_no_upload(&mut self, duration: Duration) {
+        if let Ok(upload) = tokio::time::timeout(duration, self.uploads.recv()).await {
+            panic!("agent uploaded unexpectedly: {:?}", upload);
+        }
+    }
+
+    /// Wait for the agent to upload a file.
+    pub async fn next_upload(&mut self) -> ReceivedUpload {
+        tokio::time::timeout(TIMEOUT, self.uploads.recv())
+            .await
+            .expect("timed out waiting for an upload")
+            .expect("server has stopped")
+    }
+}
+
+impl Drop for MockApi {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
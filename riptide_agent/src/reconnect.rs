@@ -0,0 +1,157 @@
+//! Policy for reconnecting to the Central-API after the websocket is lost.
+
+use std::time::Duration;
+
+use log::trace;
+use rand::Rng;
+use reqwest::Url;
+use tokio::net::TcpStream;
+
+/// How often to check if the network has come back, while waiting to reconnect.
+const NETWORK_PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait for a probe connection before giving up on it.
+const NETWORK_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Exponential backoff with jitter between reconnection attempts.
+///
+/// The first retry happens quickly, then the delay doubles with each failed attempt up to `max`. Once a
+/// connection has stayed up for `stable_after` the backoff is reset.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    initial: Duration,
+    max: Duration,
+    stable_after: Duration,
+    attempt: u32,
+}
+
+impl ReconnectPolicy {
+    /// Create a new policy, starting at `initial` and growing to at most `max`.
+    pub fn new(initial: Duration, max: Duration, stable_after: Duration) -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial,
+            max: max.max(initial),
+            stable_after,
+            attempt: 0,
+        }
+    }
+
+    /// Update the ceiling, e.g. after the configuration has been reloaded.
+    pub fn set_max(&mut self, max: Duration) {
+        self.max = max.max(self.initial);
+    }
+
+    /// Forget any previous failures, the next delay will be the initial delay.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Record that a connection was established and has now ended after `lasted`.
+    pub fn connection_ended(&mut self, lasted: Duration) {
+        if lasted >= self.stable_after {
+            self.reset();
+        }
+    }
+
+    /// The delay before the next attempt, without jitter applied.
+    fn base_delay(&self) -> Duration {
+        // cap the exponent so we don't overflow, the ceiling will have been reached long before this
+        let factor = 1u32 << self.attempt.min(16);
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+
+    /// Get the delay before the next attempt, and advance the backoff.
+    ///
+    /// Jitter is applied to the upper half of the delay, so that many agents disconnected at the same time
+    /// don't all reconnect at once.
+    pub fn next_delay(&mut self) -> Duration {
+        let base = self.base_delay();
+        self.attempt = self.attempt.saturating_add(1);
+
+        let half = base / 2;
+        let jitter = rand::thread_rng().gen_range(0.0..=1.0);
+        half + half.mul_f64(jitter)
+    }
+}
+
+/// Resolves once a TCP connection can be made to the host of `url`, used to retry immediately when the
+/// network comes back rather than waiting out the full backoff. Never resolves if the url is invalid.
+pub async fn wait_for_network(url: &str) {
+    let addr = Url::parse(url)
+        .ok()
+        .and_then(|u| Some((u.host_str()?.to_string(), u.port_or_known_default()?)));
+
+    let addr = match addr {
+        Some(addr) => addr,
+        None => return futures::future::pending().await,
+    };
+
+    loop {
+        tokio::time::sleep(NETWORK_PROBE_INTERVAL).await;
+        trace!("Probing network connection to {}:{}", addr.0, addr.1);
+        if let Ok(Ok(_)) =
+            tokio::time::timeout(NETWORK_PROBE_TIMEOUT, TcpStream::connect(addr.clone())).await
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::ReconnectPolicy;
+
+    fn policy() -> ReconnectPolicy {
+        ReconnectPolicy::new(
+            Duration::from_secs(1),
+            Duration::from_secs(60),
+            Duration::from_secs(120),
+        )
+    }
+
+    #[test]
+    fn test_backoff_grows_to_ceiling() {
+        let mut policy = policy();
+
+        let expected = [1, 2, 4, 8, 16, 32, 60, 60];
+        for max in expected {
+            let max = Duration::from_secs(max);
+            let delay = policy.next_delay();
+            assert!(delay <= max, "{:?} should be at most {:?}", delay, max);
+            assert!(
+                delay >= max / 2,
+                "{:?} should be at least {:?}",
+                delay,
+                max / 2
+            );
+        }
+    }
+
+    #[test]
+    fn test_reset_after_stable_connection() {
+        let mut policy = policy();
+        for _ in 0..10 {
+            policy.next_delay();
+        }
+
+        // a short lived connection doesn't reset the backoff
+        policy.connection_ended(Duration::from_secs(5));
+        assert!(policy.next_delay() >= Duration::from_secs(30));
+
+        policy.connection_ended(Duration::from_secs(300));
+        assert!(policy.next_delay() <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_ceiling_update() {
+        let mut policy = policy();
+        for _ in 0..10 {
+            policy.next_delay();
+        }
+
+        policy.set_max(Duration::from_secs(10));
+        assert!(policy.next_delay() <= Duration::from_secs(10));
+    }
+}
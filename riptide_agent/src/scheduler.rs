@@ -0,0 +1,470 @@
+//! Scheduling of uploads, limiting how many run at once.
+//!
+//! Uploads over the limits are queued in the order they arrived. When a slot frees up the next upload
+//! is taken from the share with the fewest running uploads, so a burst of requests for one share can't
+//! starve the others. While paused, new uploads are queued but none are started. Once closed for shutdown, new
+//! uploads are refused, while those already running or queued are left to finish.
+//!
+//! The scheduler also keeps count of the downloads of each share which are in flight, so that a share with a
+//! download limit can't be handed out more times than it has downloads left by concurrent requests.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{oneshot, Notify};
+
+#[derive(Debug)]
+struct Waiter {
+    id: u64,
+    file_id: i64,
+    tx: oneshot::Sender<UploadPermit>,
+}
+
+#[derive(Debug)]
+struct State {
+    max_uploads: usize,
+    max_uploads_per_share: usize,
+    paused: bool,
+    closed: bool,
+    running: usize,
+    running_per_share: HashMap<i64, usize>,
+    queue: VecDeque<Waiter>,
+    next_waiter_id: u64,
+    in_flight: HashMap<i64, usize>,
+}
+
+impl State {
+    fn share_running(&self, file_id: i64) -> usize {
+        self.running_per_share.get(&file_id).copied().unwrap_or(0)
+    }
+
+    fn can_start(&self, file_id: i64) -> bool {
+        !self.paused
+            && self.running < self.max_uploads
+            && self.share_running(file_id) < self.max_uploads_per_share
+    }
+
+    fn start(&mut self, file_id: i64) {
+        self.running += 1;
+        *self.running_per_share.entry(file_id).or_insert(0) += 1;
+    }
+
+    fn finish(&mut self, file_id: i64) {
+        self.running -= 1;
+        if let Some(n) = self.running_per_share.get_mut(&file_id) {
+            *n -= 1;
+            if *n == 0 {
+                self.running_per_share.remove(&file_id);
+            }
+        }
+    }
+
+    /// Find the next waiter that is allowed to start, preferring shares with the fewest running uploads.
+    fn next_waiter(&self) -> Option<usize> {
+        if self.paused || self.running >= self.max_uploads {
+            return None;
+        }
+        self.queue
+            .iter()
+            .enumerate()
+            .filter(|(_, w)| self.can_start(w.file_id))
+            .min_by_key(|(i, w)| (self.share_running(w.file_id), *i))
+            .map(|(i, _)| i)
+    }
+}
+
+/// Limits the number of concurrent uploads, both overall and per share.
+#[derive(Debug)]
+pub struct Scheduler {
+    state: Mutex<State>,
+    idle: Notify,
+}
+
+/// Permission to run an upload, the slot is released when this is dropped.
+#[derive(Debug)]
+pub struct UploadPermit {
+    scheduler: Arc<Scheduler>,
+    file_id: i64,
+}
+
+impl Drop for UploadPermit {
+    fn drop(&mut self) {
+        self.scheduler.state.lock().unwrap().finish(self.file_id);
+        self.scheduler.dispatch();
+        if self.scheduler.counts() == (0, 0) {
+            self.scheduler.idle.notify_one();
+        }
+    }
+}
+
+/// A download of a share which has been accepted but not yet recorded, released when this is dropped.
+#[derive(Debug)]
+pub struct DownloadReservation {
+    scheduler: Arc<Scheduler>,
+    file_id: i64,
+    in_flight: usize,
+}
+
+impl DownloadReservation {
+    /// How many downloads of the share were in flight when this was made, including this one.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+}
+
+impl Drop for DownloadReservation {
+    fn drop(&mut self) {
+        let mut state = self.scheduler.state.lock().unwrap();
+        if let Some(n) = state.in_flight.get_mut(&self.file_id) {
+            *n -= 1;
+            if *n == 0 {
+                state.in_flight.remove(&self.file_id);
+            }
+        }
+    }
+}
+
+/// A place in the upload queue, given up if this is dropped before the upload starts.
+#[derive(Debug)]
+pub struct Ticket {
+    scheduler: Arc<Scheduler>,
+    id: u64,
+    rx: oneshot::Receiver<UploadPermit>,
+}
+
+impl Ticket {
+    /// Wait until this upload is allowed to start.
+    pub async fn wait(mut self) -> UploadPermit {
+        (&mut self.rx)
+            .await
+            .expect("scheduler dropped a queued upload without starting it")
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.scheduler
+            .state
+            .lock()
+            .unwrap()
+            .queue
+            .retain(|w| w.id != self.id);
+        if self.scheduler.counts() == (0, 0) {
+            self.scheduler.idle.notify_one();
+        }
+    }
+}
+
+/// The result of asking the scheduler to start an upload.
+#[derive(Debug)]
+pub enum Admission {
+    /// The upload may start immediately.
+    Ready(UploadPermit),
+    /// The upload has been queued, at the given (1-based) position.
+    Queued(usize, Ticket),
+    /// The scheduler has been closed, and the upload may not run.
+    Closed,
+}
+
+impl Scheduler {
+    /// Create a new scheduler with the provided limits, a limit of zero is treated as one.
+    pub fn new(max_uploads: u64, max_uploads_per_share: u64) -> Scheduler {
+        Scheduler {
+            state: Mutex::new(State {
+                max_uploads: (max_uploads as usize).max(1),
+                max_uploads_per_share: (max_uploads_per_share as usize).max(1),
+                paused: false,
+                closed: false,
+                running: 0,
+                running_per_share: HashMap::new(),
+                queue: VecDeque::new(),
+                next_waiter_id: 0,
+                in_flight: HashMap::new(),
+            }),
+            idle: Notify::new(),
+        }
+    }
+
+    /// Update the limits, e.g. after the configuration has been reloaded.
+    pub fn set_limits(self: &Arc<Self>, max_uploads: u64, max_uploads_per_share: u64) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.max_uploads = (max_uploads as usize).max(1);
+            state.max_uploads_per_share = (max_uploads_per_share as usize).max(1);
+        }
+        self.dispatch();
+    }
+
+    /// Pause or resume starting uploads, uploads which are already running are not affected.
+    pub fn set_paused(self: &Arc<Self>, paused: bool) {
+        self.state.lock().unwrap().paused = paused;
+        self.dispatch();
+    }
+
+    /// Whether starting uploads is paused.
+    pub fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+
+    /// Refuse any further uploads, so that [`Scheduler::wait_idle`] can't miss one admitted while shutting down.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+    }
+
+    /// Request to start an upload of the given share.
+    pub fn admit(self: &Arc<Self>, file_id: i64) -> Admission {
+        let mut state = self.state.lock().unwrap();
+        self.admit_locked(&mut state, file_id)
+    }
+
+    /// Request to start an upload of the given share, only if no other upload of it is running or queued.
+    pub fn admit_exclusive(self: &Arc<Self>, file_id: i64) -> Option<Admission> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Some(Admission::Closed);
+        }
+        if state.share_running(file_id) > 0 || state.queue.iter().any(|w| w.file_id == file_id) {
+            return None;
+        }
+        Some(self.admit_locked(&mut state, file_id))
+    }
+
+    fn admit_locked(self: &Arc<Self>, state: &mut State, file_id: i64) -> Admission {
+        if state.closed {
+            return Admission::Closed;
+        }
+
+        // anything still queued is waiting on a limit, so we only need to check our own limits here
+        if state.can_start(file_id) {
+            state.start(file_id);
+            return Admission::Ready(UploadPermit {
+                scheduler: self.clone(),
+                file_id,
+            });
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let id = state.next_waiter_id;
+        state.next_waiter_id += 1;
+        state.queue.push_back(Waiter { id, file_id, tx });
+        let ticket = Ticket {
+            scheduler: self.clone(),
+            id,
+            rx,
+        };
+        Admission::Queued(state.queue.len(), ticket)
+    }
+
+    /// Count a download of the share as in flight until the reservation is dropped.
+    ///
+    /// The reservation should be made before the share's recorded downloads are read, and only dropped once
+    /// the download has been recorded. Then every download is counted by at least one of the two, and the
+    /// share can be refused if `in_flight` is more than it has downloads left.
+    pub fn reserve_download(self: &Arc<Self>, file_id: i64) -> DownloadReservation {
+        let mut state = self.state.lock().unwrap();
+        let n = state.in_flight.entry(file_id).or_insert(0);
+        *n += 1;
+        DownloadReservation {
+            scheduler: self.clone(),
+            file_id,
+            in_flight: *n,
+        }
+    }
+
+    /// The number of uploads currently running and queued.
+    pub fn counts(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        (state.running, state.queue.len())
+    }
+
+    /// Wait until there are no uploads running or queued.
+    pub async fn wait_idle(&self) {
+        loop {
+            if self.counts() == (0, 0) {
+                return;
+            }
+            self.idle.notified().await;
+        }
+    }
+
+    /// Start as many queued uploads as the limits allow.
+    fn dispatch(self: &Arc<Self>) {
+        loop {
+            let (tx, permit) = {
+                let mut state = self.state.lock().unwrap();
+                let waiter = match state.next_waiter() {
+                    Some(i) => state.queue.remove(i).unwrap(),
+                    None => return,
+                };
+                state.start(waiter.file_id);
+                let permit = UploadPermit {
+                    scheduler: self.clone(),
+                    file_id: waiter.file_id,
+                };
+                (waiter.tx, permit)
+            };
+
+            // if the waiter has gone away the permit is handed back and dropped, releasing the slot
+            let _ = tx.send(permit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{Admission, Scheduler};
+
+    #[tokio::test]
+    async fn test_limits_and_fairness() {
+        let scheduler = Arc::new(Scheduler::new(2, 2));
+
+        let a1 = match scheduler.admit(1) {
+            Admission::Ready(p) => p,
+            _ => panic!("first upload should start immediately"),
+        };
+        let a2 = match scheduler.admit(1) {
+            Admission::Ready(p) => p,
+            _ => panic!("second upload should start immediately"),
+        };
+        // global limit reached, everything else is queued in order
+        let a3 = match scheduler.admit(1) {
+            Admission::Queued(position, ticket) => {
+                assert_eq!(position, 1);
+                ticket
+            }
+            _ => panic!("global limit should have been enforced"),
+        };
+        let b1 = match scheduler.admit(2) {
+            Admission::Queued(position, ticket) => {
+                assert_eq!(position, 2);
+                ticket
+            }
+            _ => panic!("global limit should have been enforced"),
+        };
+        assert_eq!(scheduler.counts(), (2, 2));
+
+        // share 1 already has an upload running, so share 2 should go next
+        drop(a1);
+        let _b1 = b1.wait().await;
+        assert_eq!(scheduler.counts(), (2, 1));
+
+        drop(a2);
+        let _a3 = a3.wait().await;
+        assert_eq!(scheduler.counts(), (2, 0));
+    }
+
+    #[tokio::test]
+    async fn test_share_limit() {
+        let scheduler = Arc::new(Scheduler::new(4, 1));
+
+        let _a1 = match scheduler.admit(1) {
+            Admission::Ready(p) => p,
+            _ => panic!("first upload should start immediately"),
+        };
+        assert!(matches!(scheduler.admit(1), Admission::Queued(1, _)));
+        // a different share is not held up by the queued upload
+        assert!(matches!(scheduler.admit(2), Admission::Ready(_)));
+    }
+
+    #[tokio::test]
+    async fn test_exclusive_admission() {
+        let scheduler = Arc::new(Scheduler::new(1, 1));
+
+        let first = match scheduler.admit_exclusive(1) {
+            Some(Admission::Ready(p)) => p,
+            _ => panic!("first upload should start immediately"),
+        };
+        assert!(scheduler.admit_exclusive(1).is_none());
+        // other shares are only subject to the usual limits
+        let queued = scheduler.admit_exclusive(2);
+        assert!(matches!(queued, Some(Admission::Queued(1, _))));
+        assert!(scheduler.admit_exclusive(2).is_none());
+
+        drop(first);
+        assert!(scheduler.admit_exclusive(1).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let scheduler = Arc::new(Scheduler::new(1, 1));
+
+        let running = match scheduler.admit(1) {
+            Admission::Ready(p) => p,
+            _ => panic!("first upload should start immediately"),
+        };
+        let queued = match scheduler.admit(2) {
+            Admission::Queued(_, ticket) => ticket,
+            _ => panic!("global limit should have been enforced"),
+        };
+
+        scheduler.close();
+        assert!(matches!(scheduler.admit(3), Admission::Closed));
+        assert!(matches!(
+            scheduler.admit_exclusive(4),
+            Some(Admission::Closed)
+        ));
+
+        // uploads admitted before closing still run
+        drop(running);
+        drop(queued.wait().await);
+        scheduler.wait_idle().await;
+    }
+
+    #[tokio::test]
+    async fn test_download_reservations() {
+        let scheduler = Arc::new(Scheduler::new(1, 1));
+
+        let first = scheduler.reserve_download(1);
+        let second = scheduler.reserve_download(1);
+        assert_eq!(first.in_flight(), 1);
+        assert_eq!(second.in_flight(), 2);
+        assert_eq!(scheduler.reserve_download(2).in_flight(), 1);
+
+        drop(first);
+        drop(second);
+        assert_eq!(scheduler.reserve_download(1).in_flight(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pause() {
+        let scheduler = Arc::new(Scheduler::new(2, 2));
+
+        scheduler.set_paused(true);
+        let ticket = match scheduler.admit(1) {
+            Admission::Queued(position, ticket) => {
+                assert_eq!(position, 1);
+                ticket
+            }
+            _ => panic!("uploads should not start while paused"),
+        };
+
+        scheduler.set_paused(false);
+        let _permit = ticket.wait().await;
+        assert_eq!(scheduler.counts(), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn test_dropped_ticket_releases_slot() {
+        let scheduler = Arc::new(Scheduler::new(1, 1));
+
+        let first = match scheduler.admit(1) {
+            Admission::Ready(p) => p,
+            _ => panic!("first upload should start immediately"),
+        };
+        match scheduler.admit(2) {
+            Admission::Queued(_, ticket) => drop(ticket),
+            _ => panic!("global limit should have been enforced"),
+        }
+        // the dropped ticket gives up its place straight away
+        assert_eq!(scheduler.counts(), (1, 0));
+        assert!(matches!(scheduler.admit(4), Admission::Queued(1, _)));
+        drop(first);
+
+        assert_eq!(scheduler.counts(), (0, 0));
+        assert!(matches!(scheduler.admit(3), Admission::Ready(_)));
+    }
+}
@@ -0,0 +1,186 @@
+//! Tracking for the current websocket session with the Central-API.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+
+use crate::error::AgentError;
+
+/// Close codes the Central-API uses to say the agent isn't allowed to connect, in addition to
+/// [`CloseCode::Policy`]. These mirror the HTTP 401 and 403 status codes.
+const UNAUTHORIZED_CLOSE_CODES: [u16; 2] = [4001, 4003];
+
+/// Records the state of the connection to the Central-API, so that it can be reported back in status requests.
+#[derive(Debug, Default)]
+pub struct Session {
+    connected_at: Option<Instant>,
+    connections: u64,
+    last_disconnect_reason: Option<String>,
+    last_error: Option<String>,
+    rejected: Option<String>,
+}
+
+/// How a websocket session ended, which decides what the agent does next.
+#[derive(Debug)]
+pub enum SessionOutcome {
+    /// The agent is shutting down.
+    Shutdown,
+    /// The connection details changed, reconnect straight away.
+    Reconfigured,
+    /// The connection was closed normally, or dropped without a close frame. Reconnect with backoff.
+    Closed(String),
+    /// The server is going away, e.g. to restart. Reconnect quickly.
+    GoingAway(String),
+    /// The server refused the agent, e.g. because its registration is no longer valid. There is no point
+    /// reconnecting until the agent has been re-registered.
+    Rejected(String),
+    /// The connection failed.
+    Failed(AgentError),
+}
+
+impl SessionOutcome {
+    /// Decide what to do after the server closed the connection with `frame`.
+    pub fn from_close(frame: Option<&CloseFrame<'_>>) -> SessionOutcome {
+        let frame = match frame {
+            Some(frame) => frame,
+            None => return SessionOutcome::Closed(String::from("closed by server")),
+        };
+
+        let code = u16::from(frame.code);
+        let reason = if frame.reason.is_empty() {
+            format!("code {}", code)
+        } else {
+            format!("{} (code {})", frame.reason, code)
+        };
+        match frame.code {
+            CloseCode::Away | CloseCode::Restart | CloseCode::Again => {
+                SessionOutcome::GoingAway(reason)
+            }
+            CloseCode::Policy => SessionOutcome::Rejected(reason),
+            _ if UNAUTHORIZED_CLOSE_CODES.contains(&code) => SessionOutcome::Rejected(reason),
+            _ => SessionOutcome::Closed(reason),
+        }
+    }
+}
+
+impl std::fmt::Display for SessionOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionOutcome::Shutdown => write!(f, "agent shutting down"),
+            SessionOutcome::Reconfigured => write!(f, "connection details changed"),
+            SessionOutcome::Closed(reason) => write!(f, "connection closed: {}", reason),
+            SessionOutcome::GoingAway(reason) => write!(f, "server going away: {}", reason),
+            SessionOutcome::Rejected(reason) => write!(f, "rejected by server: {}", reason),
+            SessionOutcome::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Session {
+    /// Record that a new websocket connection has been established.
+    pub fn connected(&mut self) {
+        self.connected_at = Some(Instant::now());
+        self.connections += 1;
+        self.rejected = None;
+    }
+
+    /// Record that the current websocket connection has ended, and why.
+    pub fn disconnected<S: Into<String>>(&mut self, reason: S) {
+        self.connected_at = None;
+        self.last_disconnect_reason = Some(reason.into());
+    }
+
+    /// Record that the server refused the agent, we won't reconnect until the agent is re-registered.
+    pub fn rejected<S: Into<String>>(&mut self, reason: S) {
+        self.rejected = Some(reason.into());
+    }
+
+    /// Why the server refused the agent, if it has done so since we were last connected.
+    pub fn rejection(&self) -> Option<&str> {
+        self.rejected.as_deref()
+    }
+
+    /// Record an error, so that it can be reported to the cli.
+    pub fn error<S: Into<String>>(&mut self, error: S) {
+        self.last_error = Some(error.into());
+    }
+
+    /// The most recent error encountered by the agent, if there has been one.
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    /// Whether the websocket is currently connected.
+    pub fn is_connected(&self) -> bool {
+        self.connected_at.is_some()
+    }
+
+    /// How long the current connection has been established for, zero if not connected.
+    pub fn uptime(&self) -> Duration {
+        self.connected_at
+            .map(|t| t.elapsed())
+            .unwrap_or(Duration::ZERO)
+    }
+
+    /// The number of connections established since the agent started.
+    pub fn connections(&self) -> u64 {
+        self.connections
+    }
+
+    /// The number of times we have reconnected since the agent started.
+    pub fn reconnects(&self) -> u64 {
+        self.connections.saturating_sub(1)
+    }
+
+    /// The reason the last connection ended, if there has been one.
+    pub fn last_disconnect_reason(&self) -> Option<&str> {
+        self.last_disconnect_reason.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+
+    use super::SessionOutcome;
+
+    fn outcome(code: CloseCode, reason: &'static str) -> SessionOutcome {
+        SessionOutcome::from_close(Some(&CloseFrame {
+            code,
+            reason: reason.into(),
+        }))
+    }
+
+    #[test]
+    fn test_outcome_from_close() {
+        assert!(matches!(
+            SessionOutcome::from_close(None),
+            SessionOutcome::Closed(_)
+        ));
+        assert!(matches!(
+            outcome(CloseCode::Normal, ""),
+            SessionOutcome::Closed(r) if r == "code 1000"
+        ));
+        assert!(matches!(
+            outcome(CloseCode::Away, "restarting"),
+            SessionOutcome::GoingAway(r) if r == "restarting (code 1001)"
+        ));
+        assert!(matches!(
+            outcome(CloseCode::Restart, ""),
+            SessionOutcome::GoingAway(_)
+        ));
+        assert!(matches!(
+            outcome(CloseCode::Policy, "bad passcode"),
+            SessionOutcome::Rejected(_)
+        ));
+        assert!(matches!(
+            outcome(CloseCode::from(4001), "unauthorized"),
+            SessionOutcome::Rejected(_)
+        ));
+        assert!(matches!(
+            outcome(CloseCode::Error, ""),
+            SessionOutcome::Closed(_)
+        ));
+    }
+}
@@ -0,0 +1,913 @@
+//! End to end tests of the agent, run against the fake Central-API in [`crate::mock_api`].
+
+use std::{path::Path, time::Duration};
+
+use riptide_archive::{ArchiveFormat, ArchiveOptions};
+use riptide_config::Config;
+use riptide_database::{establish_connection, Share, ShareKind};
+use tempfile::TempDir;
+use tokio::task::JoinHandle;
+use warp::http::StatusCode;
+use ws_com_framework::{error::ErrorKind, Message};
+
+use crate::{
+    apply_config,
+    mock_api::{Event, MockApi, MockConnection, TIMEOUT},
+    run, Context,
+};
+
+const PUBLIC_ID: u64 = 42;
+const PRIVATE_KEY: [u8; 4] = [1, 2, 3, 4];
+
+/// An agent running against a [`MockApi`], with its file store and database in a temporary directory.
+struct TestAgent {
+    ctx: Context,
+    runner: JoinHandle<()>,
+    dir: TempDir,
+}
+
+/// Config for an agent connecting to `api` as `public_id`, with any `extra` settings appended.
+fn test_config(api: &MockApi, dir: &Path, public_id: u64, extra: &str) -> Config {
+    let config = format!(
+        r#"
+        public_id = {public_id}
+        private_key = {private_key:?}
+        websocket_address = "{websocket_address}"
+        server_address = "http://127.0.0.1:1"
+        file_store_location = "{file_store}"
+        database_location = "{database}"
+        max_upload_attempts = 2
+        size_limit_bytes = 1000000
+        reconnect_delay_minutes = 0
+        {extra}
+        "#,
+        public_id = public_id,
+        private_key = PRIVATE_KEY,
+        websocket_address = api.websocket_address(),
+        file_store = dir.join("files").display(),
+        database = dir.join("riptide.db").display(),
+        extra = extra,
+    );
+    toml::from_str(&config).unwrap()
+}
+
+impl TestAgent {
+    async fn start(api: &MockApi) -> TestAgent {
+        TestAgent::start_with(api, "").await
+    }
+
+    async fn start_with(api: &MockApi, extra: &str) -> TestAgent {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("files")).unwrap();
+        let ctx = Context::new(test_config(api, dir.path(), PUBLIC_ID, extra));
+        let runner = tokio::task::spawn(run(ctx.clone()));
+        TestAgent { ctx, runner, dir }
+    }
+
+    /// Reload the agent with a new config, as the cli would after editing the config file.
+    async fn reload(&self, api: &MockApi, public_id: u64, extra: &str) {
+        let config = test_config(api, self.dir.path(), public_id, extra);
+        apply_config(&self.ctx, config).await.unwrap();
+    }
+
+    fn database_location(&self) -> String {
+        self.dir.path().join("riptide.db").display().to_string()
+    }
+
+    /// Share `contents` as the cli would, by storing the file and adding it to the database.
+    fn add_share(&self, file_id: i64, contents: &[u8]) -> Share {
+        let share = Share {
+            file_id,
+            exp: i64::MAX,
+            crt: 0,
+            file_size: contents.len() as i64,
+            user_name: String::from("user"),
+            file_name: String::from("file.txt"),
+            broken_reason: None,
+            max_downloads: None,
+            downloads: 0,
+            one_time: false,
+            archive_format: None,
+            kind: ShareKind::File,
+            source_path: None,
+            archive_options: None,
+            source_fingerprint: None,
+        };
+        std::fs::write(self.file_location(file_id), contents).unwrap();
+        let mut conn = establish_connection(&self.database_location()).unwrap();
+        riptide_database::insert_share(&mut conn, &share).unwrap();
+        share
+    }
+
+    /// Share `dir` as a live archive, which the agent generates as it uploads.
+    fn add_live_archive(&self, file_id: i64, dir: &Path) -> Share {
+        let options = ArchiveOptions {
+            format: ArchiveFormat::Tar,
+            ..Default::default()
+        };
+        let entries = riptide_archive::collect_entries(dir, &options).unwrap();
+        let share = Share {
+            file_size: riptide_archive::tar_size(&entries) as i64,
+            file_name: String::from("project.tar"),
+            archive_format: Some(String::from("tar")),
+            kind: ShareKind::LiveArchive,
+            source_path: Some(dir.display().to_string()),
+            archive_options: Some(options.encode()),
+            source_fingerprint: Some(riptide_archive::fingerprint(&entries)),
+            ..self.add_share(file_id, b"")
+        };
+        std::fs::remove_file(self.file_location(file_id)).unwrap();
+        let mut conn = establish_connection(&self.database_location()).unwrap();
+        riptide_database::remove_share(&mut conn, file_id as u32).unwrap();
+        riptide_database::insert_share(&mut conn, &share).unwrap();
+        share
+    }
+
+    /// Replace a share in the database, e.g. to give it a download limit.
+    fn update_share(&self, share: &Share) {
+        let mut conn = establish_connection(&self.database_location()).unwrap();
+        riptide_database::remove_share(&mut conn, share.file_id as u32).unwrap();
+        riptide_database::insert_share(&mut conn, share).unwrap();
+    }
+
+    fn file_location(&self, file_id: i64) -> std::path::PathBuf {
+        self.dir.path().join("files").join(file_id.to_string())
+    }
+
+    fn share(&self, file_id: i64) -> Option<Share> {
+        let mut conn = establish_connection(&self.database_location()).unwrap();
+        riptide_database::get_share_by_id(&mut conn, &(file_id as u32)).unwrap()
+    }
+
+    /// Wait until `f` holds for the share, which the agent updates after replying to the server.
+    async fn wait_for_share<F>(&self, file_id: i64, f: F) -> Option<Share>
+    where
+        F: Fn(&Option<Share>) -> bool,
+    {
+        tokio::time::timeout(TIMEOUT, async {
+            loop {
+                let share = self.share(file_id);
+                if f(&share) {
+                    return share;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting for the share to be updated")
+    }
+}
+
+fn is_error(m: &Message) -> bool {
+    matches!(m, Message::Error { .. })
+}
+
+fn is_status(m: &Message) -> bool {
+    matches!(m, Message::StatusRes { .. })
+}
+
+/// Ask the agent for its status, and wait for the reply.
+async fn request_status(conn: &mut MockConnection, upload_id: &str) -> Message {
+    conn.send(Message::StatusReq {
+        public_id: PUBLIC_ID,
+        upload_id: upload_id.to_string(),
+    });
+    conn.recv_matching(|m| matches!(m, Message::StatusRes { upload_id: id, .. } if id == upload_id))
+        .await
+}
+
+#[tokio::test]
+async fn test_auth() {
+    let mut api = MockApi::start().await;
+    let _agent = TestAgent::start(&api).await;
+    let mut conn = api.accept().await;
+    assert_eq!(conn.public_id, PUBLIC_ID);
+
+    conn.send(Message::AuthReq {
+        public_id: PUBLIC_ID,
+    });
+    match conn.recv_matching(|m| !is_status(m)).await {
+        Message::AuthRes {
+            public_id,
+            passcode,
+        } => {
+            assert_eq!(public_id, PUBLIC_ID);
+            assert_eq!(passcode, PRIVATE_KEY.to_vec());
+        }
+        m => panic!("unexpected reply: {:?}", m),
+    }
+}
+
+#[tokio::test]
+async fn test_status() {
+    let mut api = MockApi::start().await;
+    let _agent = TestAgent::start(&api).await;
+    let mut conn = api.accept().await;
+
+    match request_status(&mut conn, "status").await {
+        Message::StatusRes { ready, message, .. } => {
+            assert!(ready, "agent not ready: {:?}", message);
+        }
+        m => panic!("unexpected reply: {:?}", m),
+    }
+}
+
+#[tokio::test]
+async fn test_metadata() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+    agent.add_share(7, b"hello world");
+    let mut conn = api.accept().await;
+
+    conn.send(Message::MetadataReq {
+        file_id: 7,
+        upload_id: String::from("abc"),
+    });
+    match conn.recv_matching(|m| !is_status(m)).await {
+        Message::MetadataRes {
+            file_id,
+            file_size,
+            username,
+            file_name,
+            upload_id,
+            ..
+        } => {
+            assert_eq!(file_id, 7);
+            assert_eq!(file_size, 11);
+            assert_eq!(username, "user");
+            assert_eq!(file_name, "file.txt");
+            assert_eq!(upload_id, "abc");
+        }
+        m => panic!("unexpected reply: {:?}", m),
+    }
+
+    conn.send(Message::MetadataReq {
+        file_id: 8,
+        upload_id: String::from("def"),
+    });
+    let reply = conn.recv_matching(|m| !is_status(m)).await;
+    assert!(matches!(
+        reply,
+        Message::Error {
+            kind: ErrorKind::FileDoesntExist,
+            ..
+        }
+    ));
+}
+
+#[tokio::test]
+async fn test_upload() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+    agent.add_share(7, b"hello world");
+    let mut conn = api.accept().await;
+
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: api.upload_url("abc"),
+    });
+    let upload = api.next_upload().await;
+    assert_eq!(upload.upload_id, "abc");
+    assert_eq!(upload.body, b"hello world");
+    assert_eq!(upload.content_range, None);
+
+    match conn.recv_matching(is_status).await {
+        Message::StatusRes {
+            ready,
+            upload_id,
+            message,
+            ..
+        } => {
+            assert!(ready);
+            assert_eq!(upload_id, "abc");
+            assert_eq!(message.as_deref(), Some("complete attempt=1 sent=11"));
+        }
+        m => panic!("unexpected reply: {:?}", m),
+    }
+
+    let share = agent
+        .wait_for_share(7, |s| s.as_ref().map(|s| s.downloads) == Some(1))
+        .await;
+    assert!(share.is_some());
+}
+
+#[tokio::test]
+async fn test_upload_range() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+    agent.add_share(7, b"hello world");
+    let mut conn = api.accept().await;
+
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: format!("{}?offset=6&length=5", api.upload_url("abc")),
+    });
+    let upload = api.next_upload().await;
+    assert_eq!(upload.body, b"world");
+    assert_eq!(upload.content_range.as_deref(), Some("bytes 6-10/11"));
+    conn.recv_matching(is_status).await;
+}
+
+#[tokio::test]
+async fn test_upload_range_not_counted() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+    agent.add_share(7, b"hello world");
+    let mut conn = api.accept().await;
+
+    // only the start of the file is delivered, so the download isn't complete
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: format!("{}?offset=0&length=5", api.upload_url("abc")),
+    });
+    assert_eq!(api.next_upload().await.body, b"hello");
+    conn.recv_matching(is_status).await;
+    assert_eq!(agent.share(7).unwrap().downloads, 0);
+
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: format!("{}?offset=5", api.upload_url("abc")),
+    });
+    assert_eq!(api.next_upload().await.body, b" world");
+    agent
+        .wait_for_share(7, |s| s.as_ref().map(|s| s.downloads) == Some(1))
+        .await;
+}
+
+#[tokio::test]
+async fn test_upload_download_limit_partial_range() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+    agent.update_share(&Share {
+        max_downloads: Some(1),
+        ..agent.add_share(7, b"hello world")
+    });
+    let mut conn = api.accept().await;
+
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: format!("{}?offset=0&length=10", api.upload_url("abc")),
+    });
+    match conn.recv_matching(is_status).await {
+        Message::StatusRes { message, .. } => {
+            assert!(message.unwrap().starts_with("Upload refused"));
+        }
+        m => panic!("unexpected reply: {:?}", m),
+    }
+    api.expect_no_upload(Duration::from_millis(500)).await;
+
+    // resuming up to the end of the file completes the download
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: format!("{}?offset=6", api.upload_url("abc")),
+    });
+    assert_eq!(api.next_upload().await.body, b"world");
+    agent.wait_for_share(7, |s| s.is_none()).await;
+}
+
+#[tokio::test]
+async fn test_upload_download_limit_concurrent() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+    agent.update_share(&Share {
+        max_downloads: Some(1),
+        ..agent.add_share(7, b"hello world")
+    });
+    let mut conn = api.accept().await;
+
+    // both requests arrive before either upload has been recorded, only one may be served
+    for upload_id in ["abc", "def"] {
+        conn.send(Message::UploadTo {
+            file_id: 7,
+            upload_url: api.upload_url(upload_id),
+        });
+    }
+    let reply = conn.recv_matching(is_error).await;
+    assert!(matches!(
+        reply,
+        Message::Error {
+            kind: ErrorKind::FileDoesntExist,
+            ..
+        }
+    ));
+    api.next_upload().await;
+    api.expect_no_upload(Duration::from_millis(500)).await;
+
+    agent.wait_for_share(7, |s| s.is_none()).await;
+}
+
+#[tokio::test]
+async fn test_upload_one_time() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+    agent.update_share(&Share {
+        one_time: true,
+        ..agent.add_share(7, b"hello world")
+    });
+    let mut conn = api.accept().await;
+
+    // stopping short of the end of the file would leave the share to be downloaded again
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: format!("{}?offset=0&length=10", api.upload_url("abc")),
+    });
+    match conn.recv_matching(is_status).await {
+        Message::StatusRes { message, .. } => {
+            assert!(message.unwrap().starts_with("Upload refused"));
+        }
+        m => panic!("unexpected reply: {:?}", m),
+    }
+
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: api.upload_url("def"),
+    });
+    assert_eq!(api.next_upload().await.body, b"hello world");
+    agent.wait_for_share(7, |s| s.is_none()).await;
+    assert!(!agent.file_location(7).exists());
+
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: api.upload_url("ghi"),
+    });
+    let reply = conn.recv_matching(is_error).await;
+    assert!(matches!(
+        reply,
+        Message::Error {
+            kind: ErrorKind::FileDoesntExist,
+            ..
+        }
+    ));
+    api.expect_no_upload(Duration::from_millis(500)).await;
+}
+
+#[tokio::test]
+async fn test_upload_live_archive() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+    let dir = agent.dir.path().join("project");
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+    std::fs::write(dir.join("readme.txt"), "hello world").unwrap();
+    // larger than the pipe between the archiver and the upload
+    let big: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+    std::fs::write(dir.join("data/big.bin"), &big).unwrap();
+    let share = agent.add_live_archive(7, &dir);
+    let mut conn = api.accept().await;
+
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: api.upload_url("abc"),
+    });
+    let upload = api.next_upload().await;
+    assert_eq!(upload.body.len() as i64, share.file_size);
+    conn.recv_matching(is_status).await;
+
+    let mut tar = tar::Archive::new(upload.body.as_slice());
+    let mut files = Vec::new();
+    for entry in tar.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let path = entry.path().unwrap().display().to_string();
+        let mut contents = Vec::new();
+        std::io::Read::read_to_end(&mut entry, &mut contents).unwrap();
+        if path == "project/data/big.bin" {
+            assert!(contents == big);
+        }
+        files.push(path);
+    }
+    assert_eq!(
+        files,
+        [
+            "project/",
+            "project/data/",
+            "project/data/big.bin",
+            "project/readme.txt"
+        ]
+    );
+
+    // ranges are cut from the same archive
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: format!("{}?offset=1000&length=100000", api.upload_url("def")),
+    });
+    let range = api.next_upload().await;
+    assert!(range.body == upload.body[1000..101000]);
+    assert_eq!(
+        range.content_range,
+        Some(format!("bytes 1000-100999/{}", share.file_size))
+    );
+    conn.recv_matching(is_status).await;
+}
+
+#[tokio::test]
+async fn test_upload_live_archive_changed() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+    let dir = agent.dir.path().join("project");
+    std::fs::create_dir(&dir).unwrap();
+    std::fs::write(dir.join("readme.txt"), "hello world").unwrap();
+    agent.add_live_archive(7, &dir);
+    // edited in place, so the archive is the same size but its contents differ
+    std::fs::write(dir.join("readme.txt"), "HELLO WORLD").unwrap();
+    let file = std::fs::File::options()
+        .write(true)
+        .open(dir.join("readme.txt"))
+        .unwrap();
+    file.set_modified(std::time::UNIX_EPOCH + Duration::from_secs(1000))
+        .unwrap();
+    let mut conn = api.accept().await;
+
+    // resuming would cut the range from a different archive than the first attempt
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: format!("{}?offset=512", api.upload_url("abc")),
+    });
+    let reply = conn.recv_matching(is_error).await;
+    assert!(matches!(
+        reply,
+        Message::Error {
+            kind: ErrorKind::FileDoesntExist,
+            ..
+        }
+    ));
+
+    let share = agent
+        .wait_for_share(7, |s| {
+            s.as_ref().and_then(|s| s.broken_reason.as_ref()).is_some()
+        })
+        .await;
+    assert!(share
+        .unwrap()
+        .broken_reason
+        .unwrap()
+        .starts_with("directory has changed since it was shared"));
+}
+
+#[tokio::test]
+async fn test_upload_live_archive_missing_directory() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+    let dir = agent.dir.path().join("project");
+    std::fs::create_dir(&dir).unwrap();
+    agent.add_live_archive(7, &dir);
+    std::fs::remove_dir(&dir).unwrap();
+    let mut conn = api.accept().await;
+
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: api.upload_url("abc"),
+    });
+    let reply = conn.recv_matching(is_error).await;
+    assert!(matches!(
+        reply,
+        Message::Error {
+            kind: ErrorKind::FileDoesntExist,
+            ..
+        }
+    ));
+
+    let share = agent
+        .wait_for_share(7, |s| {
+            s.as_ref().and_then(|s| s.broken_reason.as_ref()).is_some()
+        })
+        .await;
+    assert!(share.unwrap().broken_reason.unwrap().contains("is missing"));
+}
+
+#[tokio::test]
+async fn test_upload_throttled_below_read_timeout() {
+    let mut api = MockApi::start().await;
+    // each 4KiB chunk waits longer than the read timeout for the throttle
+    let agent = TestAgent::start_with(
+        &api,
+        "upload_rate_limit_bytes_per_second = 1500\nupload_read_timeout_seconds = 1",
+    )
+    .await;
+    let contents = vec![7; 8192];
+    agent.add_share(7, &contents);
+    let mut conn = api.accept().await;
+
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: api.upload_url("abc"),
+    });
+    let upload = api.next_upload().await;
+    assert!(upload.body == contents);
+    let not_progress = |m: &Message| matches!(m, Message::StatusRes { message: Some(m), .. } if !m.starts_with("progress"));
+    match conn.recv_matching(not_progress).await {
+        Message::StatusRes { message, .. } => {
+            assert_eq!(message.as_deref(), Some("complete attempt=1 sent=8192"));
+        }
+        m => panic!("unexpected reply: {:?}", m),
+    }
+}
+
+#[tokio::test]
+async fn test_upload_rejected() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+    agent.add_share(7, b"hello world");
+    api.set_upload_status(StatusCode::FORBIDDEN);
+    let mut conn = api.accept().await;
+
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: api.upload_url("abc"),
+    });
+    api.next_upload().await;
+    match conn.recv_matching(is_status).await {
+        Message::StatusRes { ready, message, .. } => {
+            // the upload failed, but the agent itself is still able to serve files
+            assert!(ready);
+            assert!(message.unwrap().starts_with("failed attempt=1"));
+        }
+        m => panic!("unexpected reply: {:?}", m),
+    }
+    assert_eq!(agent.share(7).unwrap().downloads, 0);
+}
+
+#[tokio::test]
+async fn test_upload_missing_share() {
+    let mut api = MockApi::start().await;
+    let _agent = TestAgent::start(&api).await;
+    let mut conn = api.accept().await;
+
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: api.upload_url("abc"),
+    });
+    match conn.recv_matching(is_error).await {
+        Message::Error { kind, reason } => {
+            assert!(matches!(kind, ErrorKind::FileDoesntExist));
+            assert_eq!(reason.as_deref(), Some("abc"));
+        }
+        m => panic!("unexpected reply: {:?}", m),
+    }
+}
+
+#[tokio::test]
+async fn test_upload_missing_file() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+    agent.add_share(7, b"hello world");
+    std::fs::remove_file(agent.file_location(7)).unwrap();
+    let mut conn = api.accept().await;
+
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: api.upload_url("abc"),
+    });
+    let reply = conn.recv_matching(is_error).await;
+    assert!(matches!(
+        reply,
+        Message::Error {
+            kind: ErrorKind::FileDoesntExist,
+            ..
+        }
+    ));
+
+    let share = agent
+        .wait_for_share(7, |s| {
+            s.as_ref().and_then(|s| s.broken_reason.as_ref()).is_some()
+        })
+        .await;
+    assert_eq!(
+        share.unwrap().broken_reason.as_deref(),
+        Some("file is missing from the file store")
+    );
+}
+
+#[tokio::test]
+async fn test_upload_restored_file() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+    agent.add_share(7, b"hello world");
+    std::fs::remove_file(agent.file_location(7)).unwrap();
+    let mut conn = api.accept().await;
+
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: api.upload_url("abc"),
+    });
+    conn.recv_matching(is_error).await;
+    agent
+        .wait_for_share(7, |s| {
+            s.as_ref().and_then(|s| s.broken_reason.as_ref()).is_some()
+        })
+        .await;
+
+    std::fs::write(agent.file_location(7), b"hello world").unwrap();
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: api.upload_url("def"),
+    });
+    assert_eq!(api.next_upload().await.body, b"hello world");
+    let share = agent
+        .wait_for_share(7, |s| s.as_ref().map(|s| s.downloads) == Some(1))
+        .await;
+    assert_eq!(share.unwrap().broken_reason, None);
+}
+
+#[tokio::test]
+async fn test_reconnect_after_connection_lost() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+
+    let conn = api.accept().await;
+    drop(conn);
+
+    let mut conn = api.accept().await;
+    request_status(&mut conn, "status").await;
+    assert_eq!(agent.ctx.session.read().await.reconnects(), 1);
+}
+
+#[tokio::test]
+async fn test_reconnect_after_missed_pong() {
+    let mut api = MockApi::start().await;
+    let agent =
+        TestAgent::start_with(&api, "ping_interval_seconds = 1\npong_timeout_seconds = 1").await;
+
+    let conn = api.accept().await;
+    conn.freeze();
+
+    let mut conn = api.accept().await;
+    request_status(&mut conn, "status").await;
+    let session = agent.ctx.session.read().await;
+    assert_eq!(
+        session.last_disconnect_reason(),
+        Some("Connection to server is dead: no pong received within 1s")
+    );
+}
+
+#[tokio::test]
+async fn test_pongs_keep_connection_alive() {
+    let mut api = MockApi::start().await;
+    let _agent =
+        TestAgent::start_with(&api, "ping_interval_seconds = 1\nidle_timeout_seconds = 2").await;
+
+    let mut conn = api.accept().await;
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    request_status(&mut conn, "status").await;
+}
+
+#[tokio::test]
+async fn test_reconnect_after_server_close() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+
+    let mut conn = api.accept().await;
+    conn.close(1000, "bye");
+    assert!(matches!(conn.recv().await, Event::Closed(Some((1000, _)))));
+
+    let mut conn = api.accept().await;
+    request_status(&mut conn, "status").await;
+    assert_eq!(
+        agent.ctx.session.read().await.last_disconnect_reason(),
+        Some("connection closed: bye (code 1000)")
+    );
+}
+
+#[tokio::test]
+async fn test_reconnect_after_server_going_away() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+
+    let mut conn = api.accept().await;
+    conn.close(1001, "restarting");
+    assert!(matches!(conn.recv().await, Event::Closed(_)));
+
+    let mut conn = api.accept().await;
+    request_status(&mut conn, "status").await;
+    assert_eq!(
+        agent.ctx.session.read().await.last_disconnect_reason(),
+        Some("server going away: restarting (code 1001)")
+    );
+}
+
+#[tokio::test]
+async fn test_rejected_until_reconfigured() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+
+    let mut conn = api.accept().await;
+    conn.close(4001, "unauthorized");
+    assert!(matches!(conn.recv().await, Event::Closed(_)));
+
+    // well past the reconnection delay
+    api.expect_no_connection(Duration::from_secs(3)).await;
+    assert_eq!(
+        agent.ctx.session.read().await.rejection(),
+        Some("unauthorized (code 4001)")
+    );
+
+    // re-registering reloads the config with new connection details
+    agent.ctx.reconnect.notify_one();
+    let mut conn = api.accept().await;
+    request_status(&mut conn, "status").await;
+    assert_eq!(agent.ctx.session.read().await.rejection(), None);
+}
+
+#[tokio::test]
+async fn test_reload_new_public_id_reconnects() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+    let mut conn = api.accept().await;
+
+    agent.reload(&api, PUBLIC_ID + 1, "").await;
+    assert!(matches!(conn.recv().await, Event::Closed(_)));
+    let mut conn = api.accept().await;
+    assert_eq!(conn.public_id, PUBLIC_ID + 1);
+    request_status(&mut conn, "status").await;
+}
+
+#[tokio::test]
+async fn test_reload_new_websocket_address_reconnects() {
+    let mut api = MockApi::start().await;
+    let mut new_api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+    let mut conn = api.accept().await;
+
+    agent.reload(&new_api, PUBLIC_ID, "").await;
+    assert!(matches!(conn.recv().await, Event::Closed(_)));
+    let mut conn = new_api.accept().await;
+    request_status(&mut conn, "status").await;
+    api.expect_no_connection(Duration::from_secs(1)).await;
+}
+
+#[tokio::test]
+async fn test_reload_keeps_connection() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+    let mut conn = api.accept().await;
+
+    agent
+        .reload(
+            &api,
+            PUBLIC_ID,
+            "upload_rate_limit_bytes_per_second = 1000\nlog_level = \"debug\"",
+        )
+        .await;
+    api.expect_no_connection(Duration::from_secs(1)).await;
+    request_status(&mut conn, "status").await;
+
+    let config = agent.ctx.config.read().await;
+    assert_eq!(*config.upload_rate_limit_bytes_per_second(), 1000);
+    assert_eq!(config.log_level(), "debug");
+}
+
+#[tokio::test]
+async fn test_reload_during_upload() {
+    let mut api = MockApi::start().await;
+    // at this rate the upload would take around seven seconds
+    let agent = TestAgent::start_with(&api, "upload_rate_limit_bytes_per_second = 4096").await;
+    let contents = vec![7; 32768];
+    agent.add_share(7, &contents);
+    let mut conn = api.accept().await;
+
+    let started = std::time::Instant::now();
+    conn.send(Message::UploadTo {
+        file_id: 7,
+        upload_url: api.upload_url("abc"),
+    });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // lifting the limit lets the same attempt finish early
+    agent.reload(&api, PUBLIC_ID, "").await;
+    let upload = api.next_upload().await;
+    assert!(upload.body == contents);
+    assert!(started.elapsed() < Duration::from_secs(4));
+    let not_progress = |m: &Message| matches!(m, Message::StatusRes { message: Some(m), .. } if !m.starts_with("progress"));
+    match conn.recv_matching(not_progress).await {
+        Message::StatusRes { message, .. } => {
+            assert_eq!(message.as_deref(), Some("complete attempt=1 sent=32768"));
+        }
+        m => panic!("unexpected reply: {:?}", m),
+    }
+    api.expect_no_connection(Duration::from_millis(500)).await;
+}
+
+#[tokio::test]
+async fn test_shutdown_closes_websocket() {
+    let mut api = MockApi::start().await;
+    let agent = TestAgent::start(&api).await;
+    let mut conn = api.accept().await;
+
+    agent.ctx.shutdown.cancel();
+    match conn.recv().await {
+        Event::Message(Message::StatusRes { ready, message, .. }) => {
+            assert!(!ready);
+            assert_eq!(message.as_deref(), Some("Agent is shutting down"));
+        }
+        e => panic!("unexpected event: {:?}", e),
+    }
+    match conn.recv().await {
+        Event::Closed(frame) => {
+            assert_eq!(frame, Some((1001, String::from("agent shutting down"))));
+        }
+        e => panic!("unexpected event: {:?}", e),
+    }
+
+    tokio::time::timeout(TIMEOUT, agent.runner)
+        .await
+        .expect("agent did not stop")
+        .unwrap();
+}
@@ -0,0 +1,193 @@
+//! Bandwidth throttling for uploads, using token buckets.
+//!
+//! A global limit is shared between all uploads, and an optional limit applies to each share. Limits can be
+//! restricted to a window of the day (local time), outside of which uploads are unlimited.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::{Local, NaiveTime};
+use log::warn;
+use riptide_config::Config;
+
+/// Per share buckets which haven't been used for this long are discarded.
+const IDLE_BUCKET_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A token bucket holding up to one second worth of bytes.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Bucket {
+        Bucket {
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take `n` tokens from the bucket, returning how long to wait until they would have been available.
+    /// A rate of zero is unlimited.
+    fn take(&mut self, n: u64, rate: u64, now: Instant) -> Duration {
+        if rate == 0 {
+            return Duration::ZERO;
+        }
+
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.last = now;
+        self.tokens -= n as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate as f64)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    global: u64,
+    per_share: u64,
+    window: Option<(NaiveTime, NaiveTime)>,
+}
+
+impl Limits {
+    fn from_config(config: &Config) -> Limits {
+        let parse = |t: &Option<String>| {
+            t.as_ref()
+                .and_then(|t| match NaiveTime::parse_from_str(t, "%H:%M") {
+                    Ok(t) => Some(t),
+                    Err(e) => {
+                        warn!(
+                            "Invalid rate limit window time `{}`, expected HH:MM: {}",
+                            t, e
+                        );
+                        None
+                    }
+                })
+        };
+
+        let window = match (
+            parse(config.upload_rate_limit_window_start()),
+            parse(config.upload_rate_limit_window_end()),
+        ) {
+            (Some(start), Some(end)) => Some((start, end)),
+            _ => None,
+        };
+
+        Limits {
+            global: *config.upload_rate_limit_bytes_per_second(),
+            per_share: *config.upload_rate_limit_per_share_bytes_per_second(),
+            window,
+        }
+    }
+}
+
+/// Whether `now` falls between `start` and `end`, the window may wrap past midnight.
+fn in_window(now: NaiveTime, start: NaiveTime, end: NaiveTime) -> bool {
+    if start <= end {
+        now >= start && now < end
+    } else {
+        now >= start || now < end
+    }
+}
+
+/// Limits the rate at which upload data is sent.
+#[derive(Debug)]
+pub struct Throttle {
+    limits: Mutex<Limits>,
+    global: Mutex<Bucket>,
+    shares: Mutex<HashMap<i64, Bucket>>,
+}
+
+impl Throttle {
+    /// Create a new throttle using the limits set in the config.
+    pub fn new(config: &Config) -> Throttle {
+        let limits = Limits::from_config(config);
+        Throttle {
+            limits: Mutex::new(limits),
+            global: Mutex::new(Bucket::new(limits.global)),
+            shares: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Update the limits, e.g. after the configuration has been reloaded.
+    pub fn update(&self, config: &Config) {
+        *self.limits.lock().unwrap() = Limits::from_config(config);
+    }
+
+    /// Wait until `n` bytes of the given share are allowed to be sent.
+    pub async fn acquire(&self, file_id: i64, n: u64) {
+        let limits = *self.limits.lock().unwrap();
+        if let Some((start, end)) = limits.window {
+            if !in_window(Local::now().time(), start, end) {
+                return;
+            }
+        }
+
+        let now = Instant::now();
+        let global_wait = self.global.lock().unwrap().take(n, limits.global, now);
+        let share_wait = if limits.per_share == 0 {
+            Duration::ZERO
+        } else {
+            let mut shares = self.shares.lock().unwrap();
+            shares.retain(|_, b| now.saturating_duration_since(b.last) < IDLE_BUCKET_TIMEOUT);
+            shares
+                .entry(file_id)
+                .or_insert_with(|| Bucket::new(limits.per_share))
+                .take(n, limits.per_share, now)
+        };
+
+        let wait = global_wait.max(share_wait);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use chrono::NaiveTime;
+
+    use super::{in_window, Bucket};
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(100);
+        bucket.last = start;
+
+        // the first second worth of data is available immediately
+        assert_eq!(bucket.take(100, 100, start), Duration::ZERO);
+        // then we have to wait for more tokens
+        assert_eq!(bucket.take(50, 100, start), Duration::from_millis(500));
+        // after waiting, the debt has been paid off
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(bucket.take(100, 100, later), Duration::ZERO);
+        // unlimited never waits
+        assert_eq!(bucket.take(1_000_000, 0, later), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_in_window() {
+        let t = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+
+        assert!(in_window(t(12, 0), t(8, 0), t(18, 0)));
+        assert!(!in_window(t(18, 30), t(8, 0), t(18, 0)));
+        assert!(!in_window(t(7, 59), t(8, 0), t(18, 0)));
+
+        // wrapping past midnight
+        assert!(in_window(t(23, 0), t(22, 0), t(6, 0)));
+        assert!(in_window(t(2, 0), t(22, 0), t(6, 0)));
+        assert!(!in_window(t(12, 0), t(22, 0), t(6, 0)));
+    }
+}
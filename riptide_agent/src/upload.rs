@@ -0,0 +1,855 @@
+//! Streaming uploads of shared files to the Central-API.
+//!
+//! Most shares are uploaded from the copy of the file kept in the file store, but live archives are
+//! generated from their directory as they are uploaded, on a blocking thread which writes the tarball into
+//! a pipe that the request reads from. The directory must still archive to the same tarball as when the share
+//! was created, checked by its size and a fingerprint of its files, otherwise the server would be told the
+//! wrong size and a resumed upload would be cut from a different archive than the first attempt.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::{self, ErrorKind, SeekFrom, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::TryStreamExt;
+use log::{debug, warn};
+use reqwest::{
+    header::{CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE},
+    Body, Client, StatusCode, Url,
+};
+use riptide_archive::{ArchiveOptions, Entry};
+use riptide_config::Config;
+use riptide_control::UploadInfo;
+use riptide_database::{Share, ShareKind};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt},
+    sync::{mpsc, RwLock},
+    time::Instant,
+};
+use tokio_util::{
+    io::{ReaderStream, SyncIoBridge},
+    sync::CancellationToken,
+};
+
+use crate::{error::AgentError, throttle::Throttle};
+
+/// Base delay between upload attempts, multiplied by the attempt number.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// How often to check that an upload is still making progress.
+const PROGRESS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often to report upload progress back to the server.
+const PROGRESS_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How much of a live archive may be buffered ahead of the upload.
+const LIVE_ARCHIVE_BUFFER: usize = 64 * 1024;
+
+/// A portion of a file requested by the server, used to resume partially delivered uploads.
+///
+/// The server requests a range by adding `offset` and (optionally) `length` query parameters
+/// to the upload url, e.g. `https://example.com/upload/abc?offset=1024&length=4096`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    offset: u64,
+    length: Option<u64>,
+}
+
+impl ByteRange {
+    /// Parse the requested range from an upload url, returns `None` if the whole file is wanted.
+    pub fn from_url(url: &str) -> Result<Option<ByteRange>, AgentError> {
+        let url = Url::parse(url).map_err(|e| AgentError::InvalidRange(e.to_string()))?;
+
+        let mut offset = None;
+        let mut length = None;
+        for (key, value) in url.query_pairs() {
+            let parsed = || {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| AgentError::InvalidRange(format!("{} is not a number", key)))
+            };
+            match key.as_ref() {
+                "offset" => offset = Some(parsed()?),
+                "length" => length = Some(parsed()?),
+                _ => {}
+            }
+        }
+
+        match (offset, length) {
+            (None, None) => Ok(None),
+            (offset, length) => Ok(Some(ByteRange {
+                offset: offset.unwrap_or(0),
+                length,
+            })),
+        }
+    }
+
+    /// Whether this range runs to the end of a file of size `total`, so that delivering it completes a download.
+    pub fn reaches_end(&self, total: u64) -> bool {
+        match self.length {
+            None => true,
+            Some(length) => matches!(self.offset.checked_add(length), Some(end) if end >= total),
+        }
+    }
+
+    /// Resolve this range against the size of the file, returning the start and length to upload.
+    fn resolve(&self, total: u64) -> Result<(u64, u64), AgentError> {
+        let length = self.length.unwrap_or(total.saturating_sub(self.offset));
+        // both values come from the server, so the end of the range may not even fit in a u64
+        let in_bounds = matches!(self.offset.checked_add(length), Some(end) if end <= total);
+        if self.offset >= total || length == 0 || !in_bounds {
+            return Err(AgentError::InvalidRange(format!(
+                "offset {} and length {} are outside of file of size {}",
+                self.offset, length, total
+            )));
+        }
+        Ok((self.offset, length))
+    }
+}
+
+/// Extract the upload id from an upload url, this is the last segment of the path.
+pub fn upload_id_from_url(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let id = url.path_segments()?.next_back()?.to_string();
+    Some(id)
+}
+
+/// Check the stored file for a share is present and intact, returning why it can't be served if not.
+pub async fn verify_share_file(loc: &Path, expected_size: u64) -> Result<(), String> {
+    let metadata = match fs::metadata(loc).await {
+        Ok(m) => m,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(String::from("file is missing from the file store"))
+        }
+        Err(e) => return Err(format!("file is unreadable: {}", e)),
+    };
+
+    if !metadata.is_file() {
+        return Err(String::from("file store entry is not a file"));
+    }
+    if metadata.len() < expected_size {
+        return Err(format!(
+            "file is truncated, {} of {} bytes present",
+            metadata.len(),
+            expected_size
+        ));
+    }
+    if metadata.len() != expected_size {
+        return Err(format!(
+            "file size has changed, expected {} bytes but found {}",
+            expected_size,
+            metadata.len()
+        ));
+    }
+
+    fs::File::open(loc)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("file is unreadable: {}", e))
+}
+
+/// Check the directory of a live archive would still be archived to the same tarball as when it was shared,
+/// returning why not otherwise. Shares without a fingerprint can only be checked by the size of the tarball.
+fn live_archive_changed(size: u64, fingerprint: Option<&str>, entries: &[Entry]) -> Option<String> {
+    let total = riptide_archive::tar_size(entries);
+    if total != size {
+        return Some(format!(
+            "directory has changed since it was shared, it now archives to {} bytes rather than {}",
+            total, size
+        ));
+    }
+    match fingerprint {
+        Some(fingerprint) if riptide_archive::fingerprint(entries) != fingerprint => Some(
+            String::from("directory has changed since it was shared, files have been modified"),
+        ),
+        _ => None,
+    }
+}
+
+/// Check the directory of a live archive is present, readable and unchanged, returning why it can't be served
+/// if not.
+pub async fn verify_live_archive(share: &Share) -> Result<(), String> {
+    let dir = match share.source_path.as_deref() {
+        Some(dir) => dir,
+        None => return Err(String::from("live archive has no source directory")),
+    };
+    match fs::metadata(dir).await {
+        Ok(m) if m.is_dir() => {}
+        Ok(_) => return Err(format!("{} is not a directory", dir)),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(format!("directory {} is missing", dir))
+        }
+        Err(e) => return Err(format!("directory is unreadable: {}", e)),
+    }
+
+    fs::read_dir(dir)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("directory is unreadable: {}", e))?;
+
+    let options = live_archive_options(share).map_err(|e| e.to_string())?;
+    let entries = walk_live_archive(Path::new(dir), &options)
+        .await
+        .map_err(|e| format!("directory is unreadable: {}", e))?;
+    let fingerprint = share.source_fingerprint.as_deref();
+    match live_archive_changed(share.file_size as u64, fingerprint, &entries) {
+        Some(reason) => Err(reason),
+        None => Ok(()),
+    }
+}
+
+/// The options a live archive was shared with.
+fn live_archive_options(share: &Share) -> Result<ArchiveOptions, AgentError> {
+    match &share.archive_options {
+        Some(options) => ArchiveOptions::decode(options).map_err(|e| AgentError::Other(e.into())),
+        None => Ok(ArchiveOptions::default()),
+    }
+}
+
+/// Find everything in the directory of a live archive.
+async fn walk_live_archive(dir: &Path, options: &ArchiveOptions) -> Result<Vec<Entry>, AgentError> {
+    let (dir, options) = (dir.to_path_buf(), options.clone());
+    let entries =
+        tokio::task::spawn_blocking(move || riptide_archive::collect_entries(&dir, &options))
+            .await??;
+    Ok(entries)
+}
+
+/// Where the content of an upload comes from.
+#[derive(Debug, Clone)]
+pub enum UploadSource {
+    /// A file in the file store.
+    Stored(PathBuf),
+    /// A tarball of a directory, generated as it is uploaded.
+    LiveArchive {
+        dir: PathBuf,
+        options: ArchiveOptions,
+        /// The size of the tarball when the directory was shared.
+        size: u64,
+        /// The fingerprint of the directory when it was shared.
+        fingerprint: Option<String>,
+    },
+}
+
+impl UploadSource {
+    /// Find the content of a share.
+    pub fn for_share(share: &Share, file_store: &Path) -> Result<UploadSource, AgentError> {
+        match share.kind {
+            ShareKind::File => Ok(UploadSource::Stored(
+                file_store.join(share.file_id.to_string()),
+            )),
+            ShareKind::LiveArchive => {
+                let dir = share.source_path.as_ref().ok_or_else(|| {
+                    AgentError::Other("live archive has no source directory".into())
+                })?;
+                Ok(UploadSource::LiveArchive {
+                    dir: PathBuf::from(dir),
+                    options: live_archive_options(share)?,
+                    size: share.file_size as u64,
+                    fingerprint: share.source_fingerprint.clone(),
+                })
+            }
+        }
+    }
+}
+
+/// Discards the first bytes written to it, so that a range of a generated archive can be uploaded.
+struct SkipWriter<W> {
+    inner: W,
+    skip: u64,
+}
+
+impl<W: Write> Write for SkipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.skip >= buf.len() as u64 {
+            self.skip -= buf.len() as u64;
+            return Ok(buf.len());
+        }
+        let skipped = self.skip as usize;
+        self.skip = 0;
+        Ok(skipped + self.inner.write(&buf[skipped..])?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Content being read for an upload attempt.
+struct OpenSource {
+    reader: Pin<Box<dyn AsyncRead + Send>>,
+    /// Completes with an error if generating the content fails, never completes otherwise.
+    producer: Pin<Box<dyn Future<Output = io::Error> + Send>>,
+    start: u64,
+    length: u64,
+    total: u64,
+}
+
+/// Open the content of an upload, starting at the requested range.
+async fn open_source(
+    source: &UploadSource,
+    range: Option<ByteRange>,
+) -> Result<OpenSource, AgentError> {
+    match source {
+        UploadSource::Stored(loc) => {
+            let mut file = fs::File::open(loc).await?;
+            let total = file.metadata().await?.len();
+            let (start, length) = match range {
+                Some(r) => r.resolve(total)?,
+                None => (0, total),
+            };
+            file.seek(SeekFrom::Start(start)).await?;
+            Ok(OpenSource {
+                reader: Box::pin(file.take(length)),
+                producer: Box::pin(futures::future::pending()),
+                start,
+                length,
+                total,
+            })
+        }
+        UploadSource::LiveArchive {
+            dir,
+            options,
+            size,
+            fingerprint,
+        } => {
+            let entries = walk_live_archive(dir, options).await?;
+            if let Some(reason) = live_archive_changed(*size, fingerprint.as_deref(), &entries) {
+                return Err(AgentError::SourceChanged(reason));
+            }
+            let total = *size;
+            let (start, length) = match range {
+                Some(r) => r.resolve(total)?,
+                None => (0, total),
+            };
+
+            let (reader, writer) = tokio::io::duplex(LIVE_ARCHIVE_BUFFER);
+            let writer = SkipWriter {
+                inner: SyncIoBridge::new(writer),
+                skip: start,
+            };
+            let task = tokio::task::spawn_blocking(move || {
+                let mut writer = io::BufWriter::with_capacity(LIVE_ARCHIVE_BUFFER, writer);
+                let res = riptide_archive::write_tar(&entries, &mut writer).and_then(
+                    |(writer, skipped)| {
+                        // skipped files would leave the archive shorter than promised
+                        if skipped > 0 {
+                            return Err(io::Error::other(format!(
+                                "{} file(s) in the directory could not be read",
+                                skipped
+                            )));
+                        }
+                        writer.flush()
+                    },
+                );
+                // hand back the writer so that the pipe stays open until the error has been seen, otherwise
+                // the request could fail first with a less useful error. It's unwrapped from the buffer first,
+                // as dropping that would flush it, which can't be done from the runtime
+                (res, writer.into_parts().0)
+            });
+            let producer = async move {
+                match task.await {
+                    // once the requested range has been read the reader is dropped, so the rest of the
+                    // archive can't be written
+                    Ok((Err(e), _writer)) if e.kind() != ErrorKind::BrokenPipe => e,
+                    Ok(_) => futures::future::pending().await,
+                    Err(e) => io::Error::other(e),
+                }
+            };
+            Ok(OpenSource {
+                reader: Box::pin(reader.take(length)),
+                producer: Box::pin(producer),
+                start,
+                length,
+                total,
+            })
+        }
+    }
+}
+
+/// Whether an upload error is worth retrying, or if the upload should be abandoned.
+fn is_transient(e: &AgentError) -> bool {
+    match e {
+        AgentError::Http(e) => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+        AgentError::UploadRejected(s) => {
+            s.is_server_error()
+                || *s == StatusCode::REQUEST_TIMEOUT
+                || *s == StatusCode::TOO_MANY_REQUESTS
+        }
+        AgentError::UploadStalled => true,
+        _ => false,
+    }
+}
+
+/// Events emitted while uploading a file, reported back to the server so that it can show progress.
+///
+/// These are sent as the message of a `StatusRes`, formatted as a keyword followed by `key=value` pairs
+/// e.g. `progress attempt=1 sent=1024 total=4096`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UploadEvent {
+    /// Periodic update on how much of the file has been sent.
+    Progress { attempt: u64, sent: u64, total: u64 },
+    /// An attempt failed, and the upload will be retried.
+    Retrying { attempt: u64, reason: String },
+    /// The upload completed successfully.
+    Complete { attempt: u64, sent: u64 },
+    /// The upload failed, and will not be retried.
+    Failed { attempt: u64, reason: String },
+}
+
+impl std::fmt::Display for UploadEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadEvent::Progress {
+                attempt,
+                sent,
+                total,
+            } => write!(
+                f,
+                "progress attempt={} sent={} total={}",
+                attempt, sent, total
+            ),
+            UploadEvent::Retrying { attempt, reason } => {
+                write!(f, "retrying attempt={} reason={}", attempt, reason)
+            }
+            UploadEvent::Complete { attempt, sent } => {
+                write!(f, "complete attempt={} sent={}", attempt, sent)
+            }
+            UploadEvent::Failed { attempt, reason } => {
+                write!(f, "failed attempt={} reason={}", attempt, reason)
+            }
+        }
+    }
+}
+
+/// An upload being tracked by [`ActiveUploads`].
+#[derive(Debug)]
+struct ActiveUpload {
+    file_id: i64,
+    upload_id: String,
+    attempt: u64,
+    sent: u64,
+    total: u64,
+    started: Instant,
+}
+
+/// The uploads currently running, so that they can be listed over the control socket.
+#[derive(Debug, Default)]
+pub struct ActiveUploads {
+    next_key: AtomicU64,
+    uploads: Mutex<HashMap<u64, ActiveUpload>>,
+}
+
+impl ActiveUploads {
+    /// Start tracking an upload, it is tracked until the returned entry is dropped.
+    pub fn start(self: &Arc<Self>, file_id: i64, upload_id: String) -> ActiveUploadEntry {
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        self.uploads.lock().unwrap().insert(
+            key,
+            ActiveUpload {
+                file_id,
+                upload_id,
+                attempt: 1,
+                sent: 0,
+                total: 0,
+                started: Instant::now(),
+            },
+        );
+        ActiveUploadEntry {
+            uploads: self.clone(),
+            key,
+        }
+    }
+
+    /// List the uploads currently running, oldest first.
+    pub fn list(&self) -> Vec<UploadInfo> {
+        let uploads = self.uploads.lock().unwrap();
+        let mut list: Vec<_> = uploads.iter().collect();
+        list.sort_by_key(|(key, _)| **key);
+        list.into_iter()
+            .map(|(_, u)| UploadInfo {
+                file_id: u.file_id as u32,
+                upload_id: u.upload_id.clone(),
+                attempt: u.attempt,
+                sent: u.sent,
+                total: u.total,
+                elapsed_seconds: u.started.elapsed().as_secs(),
+            })
+            .collect()
+    }
+}
+
+/// A tracked upload, removed from [`ActiveUploads`] when dropped.
+#[derive(Debug)]
+pub struct ActiveUploadEntry {
+    uploads: Arc<ActiveUploads>,
+    key: u64,
+}
+
+impl ActiveUploadEntry {
+    /// Update the tracked progress of the upload.
+    pub fn update(&self, event: &UploadEvent) {
+        let mut uploads = self.uploads.uploads.lock().unwrap();
+        let upload = match uploads.get_mut(&self.key) {
+            Some(upload) => upload,
+            None => return,
+        };
+        match *event {
+            UploadEvent::Progress {
+                attempt,
+                sent,
+                total,
+            } => {
+                upload.attempt = attempt;
+                upload.sent = sent;
+                upload.total = total;
+            }
+            UploadEvent::Retrying { attempt, .. } => {
+                upload.attempt = attempt + 1;
+                upload.sent = 0;
+            }
+            UploadEvent::Complete { attempt, sent } => {
+                upload.attempt = attempt;
+                upload.sent = sent;
+            }
+            UploadEvent::Failed { attempt, .. } => upload.attempt = attempt,
+        }
+    }
+}
+
+impl Drop for ActiveUploadEntry {
+    fn drop(&mut self) {
+        self.uploads.uploads.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Everything needed to attempt an upload, shared between attempts.
+#[derive(Debug)]
+struct UploadTarget<'a> {
+    client: Client,
+    source: UploadSource,
+    url: &'a str,
+    range: Option<ByteRange>,
+    read_timeout: Duration,
+    progress_interval: Duration,
+    throttle: Arc<Throttle>,
+    file_id: i64,
+    events: mpsc::Sender<UploadEvent>,
+}
+
+/// Make a single attempt at streaming the file (or the requested range of it) to the target url, returning
+/// the number of bytes sent.
+async fn try_upload(target: &UploadTarget<'_>, attempt: u64) -> Result<u64, AgentError> {
+    let OpenSource {
+        reader,
+        mut producer,
+        start,
+        length,
+        total,
+    } = open_source(&target.source, target.range).await?;
+
+    let sent = Arc::new(AtomicU64::new(0));
+    let counter = sent.clone();
+    let throttle = target.throttle.clone();
+    let file_id = target.file_id;
+    // waiting on the throttle isn't the server failing to read from us, so it doesn't count towards a stall
+    let throttled = Arc::new(AtomicBool::new(false));
+    let waiting = throttled.clone();
+    let stream = ReaderStream::new(reader)
+        .and_then(move |chunk| {
+            let (throttle, waiting) = (throttle.clone(), waiting.clone());
+            async move {
+                waiting.store(true, Ordering::Relaxed);
+                throttle.acquire(file_id, chunk.len() as u64).await;
+                waiting.store(false, Ordering::Relaxed);
+                Ok(chunk)
+            }
+        })
+        .inspect_ok(move |chunk| {
+            counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        });
+
+    let mut request = target
+        .client
+        .post(target.url)
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .header(CONTENT_LENGTH, length);
+    if target.range.is_some() {
+        request = request.header(
+            CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, start + length - 1, total),
+        );
+    }
+    let request = request.body(Body::wrap_stream(stream)).send();
+    tokio::pin!(request);
+
+    // the server may stop reading from us without closing the connection, so abandon the
+    // attempt if no bytes have been sent (or no response received) within the read timeout
+    let mut interval = tokio::time::interval(PROGRESS_CHECK_INTERVAL);
+    let mut last_sent = 0;
+    let mut last_progress = Instant::now();
+    let mut last_report = Instant::now();
+    let response = loop {
+        tokio::select! {
+            res = &mut request => break res?,
+            e = &mut producer => return Err(AgentError::ReadFile(e)),
+            _ = interval.tick() => {
+                let now_sent = sent.load(Ordering::Relaxed);
+                if now_sent != last_sent || throttled.load(Ordering::Relaxed) {
+                    last_sent = now_sent;
+                    last_progress = Instant::now();
+                } else if last_progress.elapsed() > target.read_timeout {
+                    return Err(AgentError::UploadStalled);
+                }
+
+                if last_report.elapsed() >= target.progress_interval {
+                    last_report = Instant::now();
+                    // progress is best effort, never hold up the upload to report it
+                    let _ = target.events.try_send(UploadEvent::Progress {
+                        attempt,
+                        sent: now_sent,
+                        total: length,
+                    });
+                }
+            }
+        }
+    };
+
+    let status = response.status();
+    if status.is_success() {
+        Ok(sent.load(Ordering::Relaxed))
+    } else {
+        Err(AgentError::UploadRejected(status))
+    }
+}
+
+/// Upload a shared file to the server, retrying transient failures up to `max_upload_attempts` times.
+///
+/// Progress is reported through `events`, ending with either a [`UploadEvent::Complete`] or
+/// [`UploadEvent::Failed`] unless the upload was cancelled.
+pub async fn upload_file(
+    metadata: &Share,
+    config: Arc<RwLock<Config>>,
+    url: &str,
+    throttle: Arc<Throttle>,
+    cancel: CancellationToken,
+    events: mpsc::Sender<UploadEvent>,
+) -> Result<(), AgentError> {
+    let (file_store, max_attempts, connect_timeout, read_timeout) = {
+        let reader = config.read().await;
+        (
+            reader.file_store_location().clone(),
+            *reader.max_upload_attempts(),
+            Duration::from_secs(*reader.upload_connect_timeout_seconds()),
+            Duration::from_secs(*reader.upload_read_timeout_seconds()),
+        )
+    };
+
+    let range = match ByteRange::from_url(url) {
+        Ok(range) => range,
+        Err(e) => {
+            let reason = e.to_string();
+            let _ = events
+                .send(UploadEvent::Failed { attempt: 0, reason })
+                .await;
+            return Err(e);
+        }
+    };
+    if let Some(r) = range {
+        debug!(
+            "Server requested range {:?} of file {}",
+            r, metadata.file_id
+        );
+    }
+
+    let source = match UploadSource::for_share(metadata, &file_store) {
+        Ok(source) => source,
+        Err(e) => {
+            let reason = e.to_string();
+            let _ = events
+                .send(UploadEvent::Failed { attempt: 0, reason })
+                .await;
+            return Err(e);
+        }
+    };
+
+    let target = UploadTarget {
+        client: Client::builder().connect_timeout(connect_timeout).build()?,
+        source,
+        url,
+        range,
+        read_timeout,
+        progress_interval: PROGRESS_REPORT_INTERVAL,
+        throttle,
+        file_id: metadata.file_id,
+        events,
+    };
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let res = tokio::select! {
+            res = try_upload(&target, attempt) => res,
+            _ = cancel.cancelled() => Err(AgentError::Cancelled),
+        };
+
+        match res {
+            Ok(sent) => {
+                debug!("File {} uploaded to: {}", metadata.file_name, url);
+                let _ = target
+                    .events
+                    .send(UploadEvent::Complete { attempt, sent })
+                    .await;
+                return Ok(());
+            }
+            Err(AgentError::Cancelled) => return Err(AgentError::Cancelled),
+            Err(e) if is_transient(&e) && attempt < max_attempts => {
+                warn!(
+                    "Upload attempt {} of {} for file {} failed, retrying: {}",
+                    attempt, max_attempts, metadata.file_id, e
+                );
+                let reason = e.to_string();
+                let _ = target
+                    .events
+                    .send(UploadEvent::Retrying { attempt, reason })
+                    .await;
+                tokio::select! {
+                    _ = tokio::time::sleep(RETRY_DELAY * attempt as u32) => {},
+                    _ = cancel.cancelled() => return Err(AgentError::Cancelled),
+                }
+            }
+            Err(e) => {
+                let reason = e.to_string();
+                let _ = target
+                    .events
+                    .send(UploadEvent::Failed { attempt, reason })
+                    .await;
+                return Err(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ByteRange;
+    use crate::error::AgentError;
+
+    #[test]
+    fn test_range_from_url() {
+        assert_eq!(
+            ByteRange::from_url("https://localhost/upload/abc").unwrap(),
+            None
+        );
+        assert_eq!(
+            ByteRange::from_url("https://localhost/upload/abc?offset=10&length=20").unwrap(),
+            Some(ByteRange {
+                offset: 10,
+                length: Some(20)
+            })
+        );
+        assert_eq!(
+            ByteRange::from_url("https://localhost/upload/abc?offset=10").unwrap(),
+            Some(ByteRange {
+                offset: 10,
+                length: None
+            })
+        );
+        assert!(ByteRange::from_url("https://localhost/upload/abc?offset=ten").is_err());
+    }
+
+    #[test]
+    fn test_upload_event_message() {
+        let event = super::UploadEvent::Progress {
+            attempt: 2,
+            sent: 1024,
+            total: 4096,
+        };
+        assert_eq!(event.to_string(), "progress attempt=2 sent=1024 total=4096");
+    }
+
+    #[test]
+    fn test_active_uploads() {
+        let uploads = std::sync::Arc::new(super::ActiveUploads::default());
+
+        let first = uploads.start(1, String::from("a"));
+        let second = uploads.start(2, String::from("b"));
+        first.update(&super::UploadEvent::Progress {
+            attempt: 1,
+            sent: 10,
+            total: 20,
+        });
+
+        let list = uploads.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!((list[0].file_id, list[0].sent, list[0].total), (1, 10, 20));
+        assert_eq!(list[1].upload_id, "b");
+
+        drop(second);
+        assert_eq!(uploads.list().len(), 1);
+    }
+
+    #[test]
+    fn test_upload_id_from_url() {
+        assert_eq!(
+            super::upload_id_from_url("https://localhost/upload/abc?offset=10").as_deref(),
+            Some("abc")
+        );
+    }
+
+    #[test]
+    fn test_range_resolve() {
+        let range = ByteRange {
+            offset: 10,
+            length: None,
+        };
+        assert_eq!(range.resolve(100).unwrap(), (10, 90));
+
+        let range = ByteRange {
+            offset: 10,
+            length: Some(20),
+        };
+        assert_eq!(range.resolve(100).unwrap(), (10, 20));
+        assert!(range.resolve(25).is_err());
+        assert!(range.resolve(10).is_err());
+
+        let range = ByteRange {
+            offset: 1,
+            length: Some(u64::MAX),
+        };
+        assert!(matches!(
+            range.resolve(100),
+            Err(AgentError::InvalidRange(_))
+        ));
+    }
+
+    #[test]
+    fn test_range_reaches_end() {
+        let tail = ByteRange {
+            offset: 6,
+            length: None,
+        };
+        assert!(tail.reaches_end(11));
+
+        let middle = ByteRange {
+            offset: 2,
+            length: Some(4),
+        };
+        assert!(!middle.reaches_end(11));
+
+        let end = ByteRange {
+            offset: 6,
+            length: Some(5),
+        };
+        assert!(end.reaches_end(11));
+    }
+}
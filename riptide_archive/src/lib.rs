@@ -0,0 +1,1020 @@
+//! Archiving of directories, so that they can be shared as a single file.
+//!
+//! The directory is walked recursively, and everything in it is stored under the name of the directory with
+//! its unix permissions. Symlinks are either stored as links or followed, and files which can't be read are
+//! skipped with a warning rather than failing the whole share. Paths can be left out with `.gitignore` style
+//! patterns, and any `.gitignore` files within the directory can optionally be honoured too.
+//!
+//! Archives can be written as zip files, or as tarballs which are optionally compressed with gzip or zstd.
+//! The size of an uncompressed tarball can be worked out before it is written, which allows the agent to
+//! generate one while it is being uploaded.
+
+#![warn(
+    missing_docs,
+    missing_debug_implementations,
+    missing_copy_implementations,
+    trivial_casts,
+    trivial_numeric_casts,
+    unsafe_code,
+    unstable_features,
+    unused_import_braces,
+    unused_qualifications,
+    deprecated
+)]
+
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fmt,
+    fs::{self, File, Metadata},
+    io::{self, Read, Seek, Write},
+    ops::RangeInclusive,
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    time::UNIX_EPOCH,
+};
+
+use flate2::{write::GzEncoder, Compression};
+use log::{debug, warn};
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use tar::{EntryType, Header};
+use zip::{write::FileOptions, ZipWriter};
+
+/// The file formats a directory can be archived in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ArchiveFormat {
+    /// A zip file, compressed with deflate.
+    #[default]
+    Zip,
+    /// An uncompressed tarball.
+    Tar,
+    /// A tarball compressed with gzip.
+    TarGz,
+    /// A tarball compressed with zstd.
+    TarZst,
+}
+
+impl ArchiveFormat {
+    /// Every supported format, in the order they are offered to the user.
+    pub const ALL: [ArchiveFormat; 4] = [
+        ArchiveFormat::Zip,
+        ArchiveFormat::Tar,
+        ArchiveFormat::TarGz,
+        ArchiveFormat::TarZst,
+    ];
+
+    /// The name of the format, which is also the file extension it is shared with.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::TarZst => "tar.zst",
+        }
+    }
+
+    /// The compression levels the format accepts, `None` if it isn't compressed.
+    pub fn levels(&self) -> Option<RangeInclusive<i32>> {
+        match self {
+            ArchiveFormat::Zip | ArchiveFormat::TarGz => Some(0..=9),
+            ArchiveFormat::Tar => None,
+            ArchiveFormat::TarZst => Some(1..=22),
+        }
+    }
+
+    /// Check that `level` can be used with this format.
+    pub fn check_level(&self, level: i32) -> Result<(), String> {
+        match self.levels() {
+            Some(levels) if levels.contains(&level) => Ok(()),
+            Some(levels) => Err(format!(
+                "compression level for {} must be between {} and {}",
+                self,
+                levels.start(),
+                levels.end()
+            )),
+            None => Err(format!(
+                "{} archives are not compressed, use tar.gz or tar.zst to set a compression level",
+                self
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ArchiveFormat::ALL
+            .into_iter()
+            .find(|f| f.name() == s)
+            .ok_or_else(|| format!("unknown archive format `{}`", s))
+    }
+}
+
+/// Options controlling what goes into an archive, and how it is written.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ArchiveOptions {
+    /// The format to write the archive in.
+    pub format: ArchiveFormat,
+    /// The compression level to use, or the format's default if `None`.
+    pub level: Option<i32>,
+    /// Store the files and directories symlinks point to, rather than the links themselves.
+    pub follow_symlinks: bool,
+    /// `.gitignore` style patterns of paths to leave out, relative to the shared directory.
+    pub excludes: Vec<String>,
+    /// Honour `.gitignore` files found in the directory, and leave out `.git` directories.
+    pub gitignore: bool,
+}
+
+impl ArchiveOptions {
+    /// Encode the options so that they can be stored alongside a share.
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("archive options are always serializable")
+    }
+
+    /// Decode options previously stored with [`ArchiveOptions::encode`].
+    pub fn decode(s: &str) -> Result<ArchiveOptions, serde_json::Error> {
+        serde_json::from_str(s)
+    }
+}
+
+/// A single `.gitignore` style pattern.
+#[derive(Debug)]
+struct Pattern {
+    regex: Regex,
+    negated: bool,
+    dir_only: bool,
+}
+
+/// Translate a glob into a regex, `*` and `?` don't match across directories but `**` does.
+fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                i += 1;
+                if chars.get(i + 1) == Some(&'/') {
+                    // `**/` matches any number of directories, including none
+                    i += 1;
+                    out.push_str("(?:.*/)?");
+                } else {
+                    out.push_str(".*");
+                }
+            }
+            '*' => out.push_str("[^/]*"),
+            '?' => out.push_str("[^/]"),
+            '[' => match chars[i + 1..].iter().position(|c| *c == ']') {
+                Some(len) if len > 0 => {
+                    out.push('[');
+                    for (n, c) in chars[i + 1..i + 1 + len].iter().enumerate() {
+                        match c {
+                            '!' | '^' if n == 0 => out.push('^'),
+                            '\\' | '[' | '&' | '~' | '^' => {
+                                out.push('\\');
+                                out.push(*c);
+                            }
+                            c => out.push(*c),
+                        }
+                    }
+                    out.push(']');
+                    i += len + 1;
+                }
+                _ => out.push_str(r"\["),
+            },
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                out.push_str(&regex::escape(&chars[i].to_string()));
+            }
+            c => out.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+    out
+}
+
+impl Pattern {
+    /// Parse a single `.gitignore` line, returning `None` for blank lines and comments.
+    fn parse(line: &str) -> Result<Option<Pattern>, regex::Error> {
+        let mut line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        let negated = line.starts_with('!');
+        // a leading backslash escapes a literal `!` or `#`
+        if negated || line.starts_with("\\!") || line.starts_with("\\#") {
+            line = &line[1..];
+        }
+
+        let dir_only = line.ends_with('/');
+        let line = line.trim_end_matches('/');
+        if line.is_empty() {
+            return Ok(None);
+        }
+
+        // a pattern containing a slash is relative to the directory of the .gitignore, otherwise it can match
+        // at any depth
+        let regex = if line.contains('/') {
+            format!("^{}$", glob_to_regex(line.trim_start_matches('/')))
+        } else {
+            format!("^(?:.*/)?{}$", glob_to_regex(line))
+        };
+
+        Ok(Some(Pattern {
+            regex: Regex::new(&regex)?,
+            negated,
+            dir_only,
+        }))
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        (is_dir || !self.dir_only) && self.regex.is_match(path)
+    }
+}
+
+/// Patterns which apply to a directory and everything below it.
+#[derive(Debug)]
+struct Rules {
+    /// Path of the directory relative to the shared directory, ending in a slash unless it is the root.
+    base: String,
+    patterns: Vec<Pattern>,
+}
+
+impl Rules {
+    /// Whether the last pattern matching `path` ignores it, `None` if no pattern matches.
+    fn ignores(&self, path: &str, is_dir: bool) -> Option<bool> {
+        let path = path.strip_prefix(&self.base)?;
+        self.patterns
+            .iter()
+            .rev()
+            .find(|p| p.matches(path, is_dir))
+            .map(|p| !p.negated)
+    }
+}
+
+/// Read the `.gitignore` in `dir`, if there is one.
+fn read_gitignore(dir: &Path, base: &str) -> Option<Rules> {
+    let path = dir.join(".gitignore");
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Unable to read {}, ignoring it: {}", path.display(), e);
+            return None;
+        }
+    };
+
+    let mut patterns = Vec::new();
+    for line in contents.lines() {
+        match Pattern::parse(line) {
+            Ok(Some(pattern)) => patterns.push(pattern),
+            Ok(None) => {}
+            Err(e) => warn!("Invalid pattern `{}` in {}: {}", line, path.display(), e),
+        }
+    }
+    Some(Rules {
+        base: base.to_string(),
+        patterns,
+    })
+}
+
+/// What an archive entry is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    /// A directory, whose contents are stored as separate entries.
+    Directory,
+    /// A regular file.
+    File,
+    /// A symlink, with the path it points to.
+    Symlink(String),
+}
+
+/// Something to be stored in an archive.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Where the entry is on disk.
+    pub path: PathBuf,
+    /// The name of the entry within the archive, directories end in a slash.
+    pub name: String,
+    /// What the entry is.
+    pub kind: EntryKind,
+    /// Unix permission bits.
+    pub mode: u32,
+    /// Size in bytes, zero for anything but files.
+    pub size: u64,
+    /// When the entry was last modified, in seconds since the unix epoch.
+    pub mtime: u64,
+}
+
+/// When a file was last modified, in seconds since the unix epoch.
+fn mtime(metadata: &Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs())
+}
+
+/// State used while walking a directory.
+struct Walk<'a> {
+    options: &'a ArchiveOptions,
+    excludes: Rules,
+    gitignores: Vec<Rules>,
+    /// Directories we are currently inside, so that symlink loops aren't followed forever.
+    ancestors: HashSet<PathBuf>,
+    entries: Vec<Entry>,
+}
+
+impl<'a> Walk<'a> {
+    fn is_excluded(&self, path: &str, name: &OsStr, is_dir: bool) -> bool {
+        if self.options.gitignore && is_dir && name == ".git" {
+            return true;
+        }
+        if self.excludes.ignores(path, is_dir) == Some(true) {
+            return true;
+        }
+        // later (deeper) .gitignore files take precedence
+        self.gitignores
+            .iter()
+            .rev()
+            .find_map(|rules| rules.ignores(path, is_dir))
+            .unwrap_or(false)
+    }
+
+    /// Add everything in `dir` to the archive, `relative` is its path within the shared directory.
+    fn walk(&mut self, dir: &Path, relative: &str, archive_prefix: &str) -> io::Result<()> {
+        let mut children = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        children.sort_by_key(|c| c.file_name());
+
+        let canonical = fs::canonicalize(dir)?;
+        self.ancestors.insert(canonical.clone());
+        let gitignore = if self.options.gitignore {
+            read_gitignore(dir, relative)
+        } else {
+            None
+        };
+        let has_gitignore = gitignore.is_some();
+        self.gitignores.extend(gitignore);
+
+        for child in children {
+            let path = child.path();
+            let file_name = child.file_name();
+            let child_relative = format!("{}{}", relative, file_name.to_string_lossy());
+
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(m) => m,
+                Err(e) => {
+                    warn!("Skipping {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let (metadata, link_target) = if metadata.file_type().is_symlink() {
+                if self.options.follow_symlinks {
+                    match fs::metadata(&path) {
+                        Ok(m) => (m, None),
+                        Err(e) => {
+                            warn!("Skipping broken symlink {}: {}", path.display(), e);
+                            continue;
+                        }
+                    }
+                } else {
+                    match fs::read_link(&path) {
+                        Ok(target) => (metadata, Some(target.to_string_lossy().to_string())),
+                        Err(e) => {
+                            warn!("Skipping {}: {}", path.display(), e);
+                            continue;
+                        }
+                    }
+                }
+            } else {
+                (metadata, None)
+            };
+
+            let is_dir = link_target.is_none() && metadata.is_dir();
+            if self.is_excluded(&child_relative, &file_name, is_dir) {
+                debug!("Excluding {}", child_relative);
+                continue;
+            }
+
+            let mode = metadata.permissions().mode();
+            let mtime = mtime(&metadata);
+            let name = format!("{}{}", archive_prefix, child_relative);
+            if let Some(target) = link_target {
+                self.entries.push(Entry {
+                    path,
+                    name,
+                    kind: EntryKind::Symlink(target),
+                    mode,
+                    size: 0,
+                    mtime,
+                });
+            } else if is_dir {
+                if matches!(fs::canonicalize(&path), Ok(p) if self.ancestors.contains(&p)) {
+                    warn!(
+                        "Skipping {}, it links back to a parent directory",
+                        path.display()
+                    );
+                    continue;
+                }
+                self.entries.push(Entry {
+                    path: path.clone(),
+                    name: format!("{}/", name),
+                    kind: EntryKind::Directory,
+                    mode,
+                    size: 0,
+                    mtime,
+                });
+                if let Err(e) = self.walk(&path, &format!("{}/", child_relative), archive_prefix) {
+                    warn!(
+                        "Unable to read directory {}, skipping its contents: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            } else if metadata.is_file() {
+                self.entries.push(Entry {
+                    path,
+                    name,
+                    kind: EntryKind::File,
+                    mode,
+                    size: metadata.len(),
+                    mtime,
+                });
+            } else {
+                warn!("Skipping {}, it is not a regular file", path.display());
+            }
+        }
+
+        if has_gitignore {
+            self.gitignores.pop();
+        }
+        self.ancestors.remove(&canonical);
+        Ok(())
+    }
+
+    fn new(options: &'a ArchiveOptions) -> Result<Walk<'a>, regex::Error> {
+        let mut excludes = Vec::new();
+        for exclude in &options.excludes {
+            if let Some(pattern) = Pattern::parse(exclude)? {
+                excludes.push(pattern);
+            }
+        }
+
+        Ok(Walk {
+            options,
+            excludes: Rules {
+                base: String::new(),
+                patterns: excludes,
+            },
+            gitignores: Vec::new(),
+            ancestors: HashSet::new(),
+            entries: Vec::new(),
+        })
+    }
+
+    /// Add `path` to the archive as `name`, along with everything in it if it is a directory.
+    fn add_root(&mut self, path: &Path, name: &str) -> io::Result<()> {
+        let metadata = fs::metadata(path)?;
+        let mut entry = Entry {
+            path: path.to_path_buf(),
+            name: name.to_string(),
+            kind: EntryKind::File,
+            mode: metadata.permissions().mode(),
+            size: metadata.len(),
+            mtime: mtime(&metadata),
+        };
+
+        if metadata.is_dir() {
+            entry.name.push('/');
+            entry.kind = EntryKind::Directory;
+            entry.size = 0;
+            self.entries.push(entry);
+            self.walk(path, "", &format!("{}/", name))
+        } else if metadata.is_file() {
+            self.entries.push(entry);
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a regular file or directory", path.display()),
+            ))
+        }
+    }
+}
+
+/// Find everything in `dir` which should be archived, stored under the name of the directory.
+pub fn collect_entries(
+    dir: &Path,
+    options: &ArchiveOptions,
+) -> Result<Vec<Entry>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let root = dir
+        .file_name()
+        .unwrap_or_else(|| OsStr::new("unnamed_directory"))
+        .to_string_lossy()
+        .to_string();
+    let mut walk = Walk::new(options)?;
+    walk.add_root(dir, &root)?;
+    Ok(walk.entries)
+}
+
+/// The name a path given by the user is stored under in a bundle.
+///
+/// Relative paths within the current directory are kept as they are, so that `logs/*.txt` ends up in a `logs`
+/// directory, but anything else is stored under just its own name.
+fn bundle_name(path: &Path) -> Option<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy()),
+            Component::CurDir => {}
+            _ => return path.file_name().map(|n| n.to_string_lossy().to_string()),
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+/// Find everything which should be archived to bundle `paths` together, see [`bundle_name`] for where each
+/// path is stored. Directories are stored along with everything in them.
+pub fn collect_bundle_entries(
+    paths: &[PathBuf],
+    options: &ArchiveOptions,
+) -> Result<Vec<Entry>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let mut walk = Walk::new(options)?;
+    let mut names = HashSet::new();
+    for path in paths {
+        let name = bundle_name(path)
+            .ok_or_else(|| format!("unable to find a name for {}", path.display()))?;
+        if !names.insert(name.clone()) {
+            return Err(format!("more than one file would be bundled as `{}`", name).into());
+        }
+        walk.add_root(path, &name)?;
+    }
+    Ok(walk.entries)
+}
+
+/// Open a file to be archived, logging a warning if it can't be read.
+fn open_entry(entry: &Entry) -> Option<File> {
+    match File::open(&entry.path) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            warn!("Skipping {}: {}", entry.path.display(), e);
+            None
+        }
+    }
+}
+
+/// Write `entries` to an archive in the format given by `options`, returning the writer and the number of
+/// files which couldn't be read.
+pub fn write_archive<W: Write + Seek>(
+    entries: &[Entry],
+    options: &ArchiveOptions,
+    writer: W,
+) -> io::Result<(W, usize)> {
+    if let Some(level) = options.level {
+        options
+            .format
+            .check_level(level)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+
+    match options.format {
+        ArchiveFormat::Zip => write_zip(entries, options.level, writer),
+        ArchiveFormat::Tar => write_tar(entries, writer),
+        ArchiveFormat::TarGz => {
+            let level = options
+                .level
+                .map_or_else(Compression::default, |l| Compression::new(l as u32));
+            let (encoder, skipped) = write_tar(entries, GzEncoder::new(writer, level))?;
+            Ok((encoder.finish()?, skipped))
+        }
+        ArchiveFormat::TarZst => {
+            let encoder = zstd::Encoder::new(writer, options.level.unwrap_or(0))?;
+            let (encoder, skipped) = write_tar(entries, encoder)?;
+            Ok((encoder.finish()?, skipped))
+        }
+    }
+}
+
+/// Write `entries` to a zip archive.
+fn write_zip<W: Write + Seek>(
+    entries: &[Entry],
+    level: Option<i32>,
+    writer: W,
+) -> io::Result<(W, usize)> {
+    let mut zip = ZipWriter::new(writer);
+    let mut skipped = 0;
+    for entry in entries {
+        let options = FileOptions::default()
+            .unix_permissions(entry.mode)
+            .compression_level(level);
+        match &entry.kind {
+            EntryKind::Directory => zip.add_directory(entry.name.as_str(), options)?,
+            EntryKind::Symlink(target) => {
+                zip.add_symlink(entry.name.as_str(), target.as_str(), options)?
+            }
+            EntryKind::File => {
+                let mut file = match open_entry(entry) {
+                    Some(file) => file,
+                    None => {
+                        skipped += 1;
+                        continue;
+                    }
+                };
+                let options = options.large_file(entry.size >= u32::MAX as u64);
+                zip.start_file(entry.name.as_str(), options)?;
+                io::copy(&mut file, &mut zip)?;
+            }
+        }
+    }
+    Ok((zip.finish()?, skipped))
+}
+
+/// Round a size up to a whole number of tar blocks.
+fn tar_blocks(size: u64) -> u64 {
+    (size + 511) & !511
+}
+
+/// Mix `bytes` into an FNV-1a hash.
+fn fnv1a(hash: &mut u64, bytes: &[u8]) {
+    for byte in bytes {
+        *hash ^= *byte as u64;
+        *hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+}
+
+/// A fingerprint of everything [`write_tar`] stores about `entries` other than the contents of files, so that a
+/// directory can be checked to still archive to the same tarball.
+///
+/// FNV-1a is used rather than the hasher from std, as that isn't guaranteed to be stable between releases.
+pub fn fingerprint(entries: &[Entry]) -> String {
+    let mut hash = 0xcbf2_9ce4_8422_2325;
+    for entry in entries {
+        fnv1a(&mut hash, entry.name.as_bytes());
+        match &entry.kind {
+            EntryKind::Directory => fnv1a(&mut hash, b"\0d"),
+            EntryKind::File => fnv1a(&mut hash, b"\0f"),
+            EntryKind::Symlink(target) => {
+                fnv1a(&mut hash, b"\0l");
+                fnv1a(&mut hash, target.as_bytes());
+            }
+        }
+        fnv1a(&mut hash, b"\0");
+        fnv1a(&mut hash, &entry.mode.to_le_bytes());
+        fnv1a(&mut hash, &entry.size.to_le_bytes());
+        fnv1a(&mut hash, &entry.mtime.to_le_bytes());
+    }
+    format!("{:016x}", hash)
+}
+
+/// The exact size of the tarball [`write_tar`] will write for `entries`, provided none of the files can't be
+/// read when it is written.
+pub fn tar_size(entries: &[Entry]) -> u64 {
+    // the archive ends with two empty blocks
+    let mut size = 1024;
+    for entry in entries {
+        // names which don't fit in the header are stored in an extra entry before it
+        let mut header = Header::new_gnu();
+        if header.set_path(&entry.name).is_err() {
+            size += 512 + tar_blocks(entry.name.len() as u64 + 1);
+        }
+        if let EntryKind::Symlink(target) = &entry.kind {
+            if header.set_link_name(target).is_err() {
+                size += 512 + tar_blocks(target.len() as u64 + 1);
+            }
+        }
+
+        size += 512;
+        if entry.kind == EntryKind::File {
+            size += tar_blocks(entry.size);
+        }
+    }
+    size
+}
+
+/// Write `entries` to an uncompressed tarball, returning the writer and the number of files which couldn't be
+/// read.
+///
+/// Files are stored with the size they had when their entries were collected, so that the size of the
+/// tarball is known ahead of time. Files which have grown since are truncated, and those which have shrunk
+/// are padded with zeros.
+pub fn write_tar<W: Write>(entries: &[Entry], writer: W) -> io::Result<(W, usize)> {
+    let mut tar = tar::Builder::new(writer);
+    let mut skipped = 0;
+    for entry in entries {
+        let mut header = Header::new_gnu();
+        header.set_mode(entry.mode & 0o7777);
+        header.set_mtime(entry.mtime);
+        header.set_size(0);
+        match &entry.kind {
+            EntryKind::Directory => {
+                header.set_entry_type(EntryType::Directory);
+                tar.append_data(&mut header, &entry.name, io::empty())?;
+            }
+            EntryKind::Symlink(target) => {
+                header.set_entry_type(EntryType::Symlink);
+                tar.append_link(&mut header, &entry.name, target)?;
+            }
+            EntryKind::File => {
+                let file = match open_entry(entry) {
+                    Some(file) => file,
+                    None => {
+                        skipped += 1;
+                        continue;
+                    }
+                };
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(entry.size);
+                let mut data = file.take(entry.size).chain(io::repeat(0)).take(entry.size);
+                tar.append_data(&mut header, &entry.name, &mut data)?;
+                if data.into_inner().into_inner().0.limit() > 0 {
+                    warn!(
+                        "{} shrank while being archived, padded it with zeros",
+                        entry.path.display()
+                    );
+                }
+            }
+        }
+    }
+    Ok((tar.into_inner()?, skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{Read, Seek, SeekFrom},
+        os::unix::fs::PermissionsExt,
+        path::Path,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use super::{
+        bundle_name, collect_bundle_entries, collect_entries, fingerprint, tar_size, write_archive,
+        write_tar, ArchiveFormat, ArchiveOptions, EntryKind, Pattern,
+    };
+
+    fn matches(pattern: &str, path: &str, is_dir: bool) -> bool {
+        Pattern::parse(pattern)
+            .unwrap()
+            .unwrap()
+            .matches(path, is_dir)
+    }
+
+    #[test]
+    fn test_patterns() {
+        assert!(matches("*.log", "debug.log", false));
+        assert!(matches("*.log", "logs/debug.log", false));
+        assert!(!matches("*.log", "debug.log.txt", false));
+        assert!(matches("/build", "build", true));
+        assert!(!matches("/build", "src/build", true));
+        assert!(matches("target/", "target", true));
+        assert!(!matches("target/", "target", false));
+        assert!(matches("docs/*.md", "docs/readme.md", false));
+        assert!(!matches("docs/*.md", "docs/api/readme.md", false));
+        assert!(matches("docs/**/*.md", "docs/api/readme.md", false));
+        assert!(matches("**/node_modules", "a/b/node_modules", true));
+        assert!(matches("file?.[ch]", "file1.c", false));
+        assert!(!matches("file?.[!ch]", "file1.c", false));
+        assert!(Pattern::parse("# comment").unwrap().is_none());
+        assert!(Pattern::parse("!keep.log").unwrap().unwrap().negated);
+    }
+
+    #[test]
+    fn test_archive_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        fs::create_dir_all(root.join("src/nested")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::write(root.join(".gitignore"), "target/\n*.log\n!keep.log\n").unwrap();
+        fs::write(root.join("src/main.rs"), "fn main() {}").unwrap();
+        fs::write(root.join("src/nested/run.sh"), "#!/bin/sh").unwrap();
+        fs::set_permissions(
+            root.join("src/nested/run.sh"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        fs::write(root.join("target/out"), "binary").unwrap();
+        fs::write(root.join("debug.log"), "log").unwrap();
+        fs::write(root.join("keep.log"), "log").unwrap();
+        fs::write(root.join("secret.env"), "password").unwrap();
+        std::os::unix::fs::symlink("src/main.rs", root.join("link.rs")).unwrap();
+
+        let options = ArchiveOptions {
+            follow_symlinks: false,
+            excludes: vec![String::from("*.env")],
+            gitignore: true,
+            ..Default::default()
+        };
+        let entries = collect_entries(&root, &options).unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "project/",
+                "project/.gitignore",
+                "project/keep.log",
+                "project/link.rs",
+                "project/src/",
+                "project/src/main.rs",
+                "project/src/nested/",
+                "project/src/nested/run.sh",
+            ]
+        );
+        assert_eq!(
+            entries[3].kind,
+            EntryKind::Symlink(String::from("src/main.rs"))
+        );
+
+        // without .gitignore support, only the explicit excludes apply, and links can be followed
+        let options = ArchiveOptions {
+            follow_symlinks: true,
+            excludes: vec![String::from("*.env"), String::from("/.git")],
+            gitignore: false,
+            ..Default::default()
+        };
+        let entries = collect_entries(&root, &options).unwrap();
+        assert_eq!(entries.len(), 11);
+        let link = entries
+            .iter()
+            .find(|e| e.name == "project/link.rs")
+            .unwrap();
+        assert_eq!(link.kind, EntryKind::File);
+
+        let (file, skipped) =
+            write_archive(&entries, &options, tempfile::tempfile().unwrap()).unwrap();
+        assert_eq!(skipped, 0);
+        let mut zip = zip::ZipArchive::new(file).unwrap();
+        assert_eq!(zip.len(), 11);
+        let script = zip.by_name("project/src/nested/run.sh").unwrap();
+        assert_eq!(script.unix_mode().unwrap() & 0o777, 0o755);
+    }
+
+    #[test]
+    fn test_tar_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        let long = "a".repeat(120);
+        fs::create_dir_all(root.join(&long)).unwrap();
+        fs::write(root.join(&long).join("file"), vec![7; 1000]).unwrap();
+        fs::write(root.join("empty"), "").unwrap();
+        std::os::unix::fs::symlink(root.join(&long).join("file"), root.join("link")).unwrap();
+
+        let entries = collect_entries(&root, &ArchiveOptions::default()).unwrap();
+        let (tarball, skipped) = write_tar(&entries, Vec::new()).unwrap();
+        assert_eq!(skipped, 0);
+        assert_eq!(tarball.len() as u64, tar_size(&entries));
+
+        // files are stored at the size they were found with, whatever happens to them afterwards
+        fs::write(root.join("empty"), "no longer empty").unwrap();
+        fs::write(root.join(&long).join("file"), "shrunk").unwrap();
+        let (tarball, _) = write_tar(&entries, Vec::new()).unwrap();
+        assert_eq!(tarball.len() as u64, tar_size(&entries));
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("file"), "hello world").unwrap();
+
+        let options = ArchiveOptions::default();
+        let before = fingerprint(&collect_entries(&root, &options).unwrap());
+        assert_eq!(
+            before,
+            fingerprint(&collect_entries(&root, &options).unwrap())
+        );
+
+        // touching a file changes its header in the tarball, though not the size of the tarball
+        let file = fs::File::options()
+            .write(true)
+            .open(root.join("file"))
+            .unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(1000))
+            .unwrap();
+        let entries = collect_entries(&root, &options).unwrap();
+        assert_ne!(before, fingerprint(&entries));
+    }
+
+    #[test]
+    fn test_bundle() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("logs/old")).unwrap();
+        fs::write(dir.path().join("a.pdf"), "a").unwrap();
+        fs::write(dir.path().join("logs/1.txt"), "1").unwrap();
+        fs::write(dir.path().join("logs/old/0.txt"), "0").unwrap();
+
+        let options = ArchiveOptions::default();
+        let paths = [dir.path().join("a.pdf"), dir.path().join("logs/old")];
+        let entries = collect_bundle_entries(&paths, &options).unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["a.pdf", "old/", "old/0.txt"]);
+
+        // two files with the same name can't both be stored at the top of the bundle
+        let paths = [dir.path().join("a.pdf"), dir.path().join("a.pdf")];
+        assert!(collect_bundle_entries(&paths, &options).is_err());
+
+        assert_eq!(
+            bundle_name(Path::new("./logs/1.txt")).unwrap(),
+            "logs/1.txt"
+        );
+        assert_eq!(bundle_name(Path::new("../logs/1.txt")).unwrap(), "1.txt");
+        assert_eq!(bundle_name(Path::new("/var/log/syslog")).unwrap(), "syslog");
+        assert_eq!(bundle_name(Path::new(".")), None);
+    }
+
+    #[test]
+    fn test_encode_options() {
+        let options = ArchiveOptions {
+            format: ArchiveFormat::Tar,
+            excludes: vec![String::from("*.log")],
+            gitignore: true,
+            ..Default::default()
+        };
+        let decoded = ArchiveOptions::decode(&options.encode()).unwrap();
+        assert_eq!(decoded.format, ArchiveFormat::Tar);
+        assert_eq!(decoded.excludes, options.excludes);
+        assert!(decoded.gitignore);
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!("tar.zst".parse(), Ok(ArchiveFormat::TarZst));
+        assert!("rar".parse::<ArchiveFormat>().is_err());
+        assert!(ArchiveFormat::Zip.check_level(9).is_ok());
+        assert!(ArchiveFormat::TarGz.check_level(10).is_err());
+        assert!(ArchiveFormat::TarZst.check_level(19).is_ok());
+        assert!(ArchiveFormat::Tar.check_level(1).is_err());
+    }
+
+    #[test]
+    fn test_tarballs() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::write(root.join("bin/run.sh"), "#!/bin/sh").unwrap();
+        fs::set_permissions(root.join("bin/run.sh"), fs::Permissions::from_mode(0o750)).unwrap();
+        std::os::unix::fs::symlink("bin/run.sh", root.join("run")).unwrap();
+
+        for format in [
+            ArchiveFormat::Tar,
+            ArchiveFormat::TarGz,
+            ArchiveFormat::TarZst,
+        ] {
+            let options = ArchiveOptions {
+                format,
+                level: format.levels().map(|l| *l.end()),
+                ..Default::default()
+            };
+            let entries = collect_entries(&root, &options).unwrap();
+            let (mut file, skipped) =
+                write_archive(&entries, &options, tempfile::tempfile().unwrap()).unwrap();
+            assert_eq!(skipped, 0);
+            file.seek(SeekFrom::Start(0)).unwrap();
+
+            let reader: Box<dyn Read> = match format {
+                ArchiveFormat::TarGz => Box::new(flate2::read::GzDecoder::new(file)),
+                ArchiveFormat::TarZst => Box::new(zstd::Decoder::new(file).unwrap()),
+                _ => Box::new(file),
+            };
+            let mut tar = tar::Archive::new(reader);
+            let mut found = Vec::new();
+            for entry in tar.entries().unwrap() {
+                let mut entry = entry.unwrap();
+                let path = entry.path().unwrap().to_string_lossy().to_string();
+                if path == "project/bin/run.sh" {
+                    assert_eq!(entry.header().mode().unwrap(), 0o750);
+                    let mut contents = String::new();
+                    entry.read_to_string(&mut contents).unwrap();
+                    assert_eq!(contents, "#!/bin/sh");
+                } else if path == "project/run" {
+                    let target = entry.link_name().unwrap().unwrap();
+                    assert_eq!(target, Path::new("bin/run.sh"));
+                }
+                found.push(path);
+            }
+            assert_eq!(
+                found,
+                [
+                    "project/",
+                    "project/bin/",
+                    "project/bin/run.sh",
+                    "project/run"
+                ],
+                "{}",
+                format
+            );
+        }
+    }
+}
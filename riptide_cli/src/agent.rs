@@ -0,0 +1,33 @@
+//! Communication with the running agent over its control socket.
+//!
+//! The agent may not be running, so every caller must be prepared to fall back to working with the
+//! database directly.
+
+use log::{debug, trace};
+use riptide_config::Config;
+use riptide_control::{Client, Request, Response};
+use std::error::Error;
+
+/// Send a request to the agent, returns `None` if the agent can't be reached.
+pub fn request(request: Request) -> Option<Response> {
+    let path = Config::control_socket_location();
+    trace!("connecting to agent at `{}`", path.to_string_lossy());
+
+    let res = Client::connect(&path).and_then(|mut client| client.request(request));
+    match res {
+        Ok(response) => Some(response),
+        Err(e) => {
+            debug!("unable to reach agent, falling back: {}", e);
+            None
+        }
+    }
+}
+
+/// Ask the agent to reload its configuration, leaving a marker for it to pick up if it isn't running.
+pub fn reload() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    match request(Request::Reload) {
+        Some(Response::Ok) => Ok(()),
+        Some(Response::Error(e)) => Err(e.into()),
+        _ => Ok(Config::reload_agent()?),
+    }
+}
@@ -19,6 +19,96 @@ pub fn build_cli() -> Command<'static> {
                 .forbid_empty_values(true)
                 .value_parser(clap::value_parser!(i64).range(1..8760)),
         )
+        .arg(
+            Arg::new("max-downloads")
+                .help("Remove the share after the file has been downloaded this many times")
+                .short('d')
+                .long("max-downloads")
+                .takes_value(true)
+                .value_name("N")
+                .forbid_empty_values(true)
+                .value_parser(clap::value_parser!(i64).range(1..)),
+        )
+        .arg(
+            Arg::new("once")
+                .help("Remove the share as soon as the file has been downloaded once")
+                .long("once")
+                .takes_value(false)
+                .conflicts_with("max-downloads"),
+        )
+        .arg(
+            Arg::new("exclude")
+                .help("Leave paths matching this .gitignore style pattern out when sharing a directory")
+                .short('x')
+                .long("exclude")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .value_name("GLOB")
+                .forbid_empty_values(true)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("follow-symlinks")
+                .help("Archive the files symlinks point to, rather than the links themselves")
+                .long("follow-symlinks")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("gitignore")
+                .help("Honour .gitignore files when sharing a directory")
+                .long("gitignore")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("format")
+                .help("The archive format to share a directory in")
+                .short('f')
+                .long("format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .default_value("zip")
+                .value_parser(["zip", "tar", "tar.gz", "tar.zst"]),
+        )
+        .arg(
+            Arg::new("level")
+                .help("The compression level to archive a directory with, 0-9 for zip and tar.gz, 1-22 for tar.zst")
+                .long("level")
+                .takes_value(true)
+                .value_name("LEVEL")
+                .forbid_empty_values(true)
+                .value_parser(clap::value_parser!(i32)),
+        )
+        .arg(
+            Arg::new("live")
+                .help("Share a directory as a tarball generated each time it is downloaded, rather than archiving it now")
+                .long("live")
+                .takes_value(false)
+                .conflicts_with_all(&["format", "level"]),
+        )
+        .arg(
+            Arg::new("separate")
+                .help("Share each file on its own rather than bundling them together")
+                .long("separate")
+                .takes_value(false)
+                .conflicts_with("name"),
+        )
+        .arg(
+            Arg::new("name")
+                .help("The name to share the file or bundle under")
+                .short('n')
+                .long("name")
+                .takes_value(true)
+                .value_name("NAME")
+                .forbid_empty_values(true)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("stdin")
+                .help("Share what is read from standard input, the same as giving - as the file")
+                .long("stdin")
+                .takes_value(false)
+                .conflicts_with_all(&["file", "separate", "live"]),
+        )
         .arg(
             Arg::new("remove")
                 .help("Remove the file share indicated by this id by index or id")
@@ -51,14 +141,45 @@ pub fn build_cli() -> Command<'static> {
                 .long("list")
                 .takes_value(false),
         )
+        .arg(
+            Arg::new("uploads")
+                .help("List uploads the agent is currently running")
+                .long("uploads")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("pause")
+                .help("Stop the agent from starting new uploads")
+                .long("pause")
+                .takes_value(false)
+                .conflicts_with("resume"),
+        )
+        .arg(
+            Arg::new("resume")
+                .help("Allow the agent to start uploads again after a pause")
+                .long("resume")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("sweep")
+                .help("Remove expired shares now")
+                .long("sweep")
+                .takes_value(false),
+        )
         .arg(
             Arg::new("file")
-                .help("Name of the file to share")
+                .help("Names of the files to share, more than one are bundled into a single archive")
                 .required(false)
                 .index(1)
+                .multiple_values(true)
                 .allow_invalid_utf8(false)
                 .value_parser(clap::value_parser!(PathBuf)),
         )
+        .subcommand(
+            Command::new("status")
+                .about("Show the health of the agent and its connection to the server")
+                .after_help("Exit codes: 0 healthy, 1 not configured or registered, 2 agent not running, 3 agent not connected, 4 agent rejected by server"),
+        )
         .arg(
             Arg::new("reset-config")
                 .help("Reset the config file to default")
@@ -5,12 +5,36 @@
 //!
 //! Expected Syntax: `share ./myfiles/data/file.txt`
 //!
+//! More than one file (or glob) can be given, in which case they are bundled into a single archive, e.g.
+//! `share a.pdf b.pdf logs/*.txt`.
+//!
+//! Giving `-` as the file shares whatever is piped to standard input, e.g. `pg_dump | share - --name db.sql`.
+//!
+//! Commands:
+//! - `status`, reports the health of the installation and agent. Exits with 0 when healthy, 1 when not
+//!   configured or registered, 2 when the agent isn't running, 3 when it isn't connected to the server, and 4
+//!   when the server has rejected the agent.
+//!
 //! Supported Options:
 //! - `--remove :file_id`, removes a given file share.
 //! - `--list`, lists all currently shared files
 //! - `--time`, sets the amount of time (in hours) that the file should remain shared.
+//! - `--max-downloads`, sets how many times the file can be downloaded before the share is removed.
+//! - `--once`, removes the share as soon as the file has been downloaded, so the link can't be reused.
+//! - `--uploads`, lists the uploads the agent is currently running.
+//! - `--pause`/`--resume`, stops and restarts the agent starting new uploads.
+//! - `--sweep`, removes expired shares immediately.
+//! - `--exclude :glob`, leaves matching paths out when sharing a directory, can be given multiple times.
+//! - `--follow-symlinks`, archives what symlinks in a shared directory point to rather than the links.
+//! - `--gitignore`, honours `.gitignore` files when sharing a directory.
+//! - `--format :format`, the archive format to share a directory in, one of `zip`, `tar`, `tar.gz` or `tar.zst`.
+//! - `--level :level`, the compression level to archive a directory with.
+//! - `--separate`, shares each of several files on its own rather than bundling them, printing a table of links.
+//! - `--name :name`, the name to share the file or bundle under.
+//! - `--stdin`, shares what is read from standard input, the same as giving `-` as the file.
+//! - `--live`, shares a directory as a tarball which the agent generates each time it is downloaded, rather
+//!   than archiving it up front.
 
-//TODO: Support download limiting
 //TODO: support removing a file by partial id
 
 #![warn(
@@ -27,6 +51,7 @@
     deprecated
 )]
 
+mod agent;
 mod cli;
 
 // use copypasta::{ClipboardContext, ClipboardProvider};
@@ -34,17 +59,29 @@ use human_panic::setup_panic;
 use lazy_static::lazy_static;
 use log::{error, info, trace};
 use rand::Rng;
+use riptide_archive::{ArchiveFormat, ArchiveOptions};
 use riptide_config::Config;
-use riptide_database::{establish_connection, insert_share, Share};
+use riptide_control::{Request, Response};
+use riptide_database::{establish_connection, insert_share, Share, ShareKind};
 use std::error::Error;
 use std::ffi::OsStr;
 use std::fs::File;
 use std::io::Error as IoError;
-use std::io::ErrorKind;
-use std::path::PathBuf;
+use std::io::{ErrorKind, IsTerminal, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 use tempfile::tempfile;
-use zip::write::FileOptions;
+
+/// Exit code for `riptide status` when everything is working.
+const STATUS_HEALTHY: i32 = 0;
+/// Exit code for `riptide status` when the installation isn't configured or registered.
+const STATUS_NOT_CONFIGURED: i32 = 1;
+/// Exit code for `riptide status` when the agent isn't running.
+const STATUS_AGENT_NOT_RUNNING: i32 = 2;
+/// Exit code for `riptide status` when the agent is running but not connected to the server.
+const STATUS_DISCONNECTED: i32 = 3;
+/// Exit code for `riptide status` when the server has refused the agent, and it needs to be re-registered.
+const STATUS_REJECTED: i32 = 4;
 
 lazy_static! {
     /// The config file for riptide
@@ -54,68 +91,230 @@ lazy_static! {
     });
 }
 
+/// The path standing in for standard input.
+const STDIN_PATH: &str = "-";
+
+/// How shares should be created, from the command line arguments.
+#[derive(Debug)]
+struct ShareOptions {
+    /// How many hours the share lasts for
+    share_time: i64,
+    max_downloads: Option<i64>,
+    one_time: bool,
+    archive: ArchiveOptions,
+    /// Archive directories each time they are downloaded, rather than up front
+    live: bool,
+    /// The name to share the file under, rather than its own
+    name: Option<String>,
+}
+
 /// Create a share from provided arguments and configuration.
+///
+/// A single file is shared as it is, while a directory or more than one path is archived into a bundle.
 fn create_share(
-    path: &PathBuf,
-    share_time: i64,
+    paths: &[PathBuf],
+    options: &ShareOptions,
 ) -> Result<Share, Box<dyn Error + Send + Sync + 'static>> {
+    let ShareOptions {
+        share_time,
+        max_downloads,
+        one_time,
+        archive,
+        live,
+        ..
+    } = options;
+
     trace!("getting file path");
-    if !path.exists() {
+    let stdin = paths.iter().any(|p| p.as_os_str() == STDIN_PATH);
+    if stdin && (paths.len() > 1 || *live) {
         return Err(Box::new(IoError::new(
-            ErrorKind::NotFound,
-            "provided path does not exist",
+            ErrorKind::InvalidInput,
+            "standard input can only be shared on its own",
         )));
     }
-
-    // If the path is a directory, we need to create a temporary file to share
-    // request user confirmation that they want to share a directory as a compressed (zipped) file
-    let file_name;
-    let mut file;
-    if path.is_dir() {
-        println!("You are attempting to share a directory. This will be compressed into a zip file. Is this ok? (y/n)");
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).unwrap();
-        if input.trim() != "y" {
+    for path in paths.iter().filter(|_| !stdin) {
+        if !path.exists() {
             return Err(Box::new(IoError::new(
-                ErrorKind::InvalidInput,
-                "user cancelled directory share",
+                ErrorKind::NotFound,
+                format!("provided path {} does not exist", path.display()),
             )));
         }
+    }
+    let bundle = paths.len() > 1;
+    let path = &paths[0];
+    if *live && (bundle || !path.is_dir()) {
+        return Err(Box::new(IoError::new(
+            ErrorKind::InvalidInput,
+            "only a single directory can be shared as a live archive",
+        )));
+    }
 
-        // compress file into a zip, storing in tmp location
-        let temp_file = tempfile()?;
-        let mut zip = zip::ZipWriter::new(temp_file);
-        zip.add_directory(path.to_string_lossy(), FileOptions::default())?;
+    // If the path is a directory, we need to create a temporary file to share
+    // request user confirmation that they want to share a directory as an archive
+    let mut file_name;
+    let file: Option<Box<dyn Read>>;
+    let mut size = 0;
+    let mut archive_format = None;
+    let mut kind = ShareKind::File;
+    let mut source_path = None;
+    let mut archive_options = None;
+    let mut source_fingerprint = None;
+    if stdin {
+        if std::io::stdin().is_terminal() {
+            println!("Reading from standard input, press Ctrl-D to finish");
+        }
+        file_name = String::from("stdin");
+        file = Some(Box::new(std::io::stdin()));
+    } else if *live {
+        // nothing is stored for a live archive, the agent generates a tarball from the directory every time
+        // it is downloaded, so the options it needs are kept with the share
+        let dir = path.canonicalize()?;
+        let options = ArchiveOptions {
+            format: ArchiveFormat::Tar,
+            level: None,
+            ..archive.clone()
+        };
+        let entries = riptide_archive::collect_entries(&dir, &options)?;
+        println!(
+            "Found {} file(s), the directory will be archived each time it is downloaded",
+            entries
+                .iter()
+                .filter(|e| e.kind != riptide_archive::EntryKind::Directory)
+                .count()
+        );
 
         file_name = format!(
-            "{}.zip",
-            path.file_name()
+            "{}.{}",
+            dir.file_name()
                 .unwrap_or_else(|| OsStr::new("unnamed_directory"))
-                .to_string_lossy()
+                .to_string_lossy(),
+            options.format
         );
-        file = zip.finish()?;
+        file = None;
+        size = riptide_archive::tar_size(&entries);
+        let limit = *CONFIG.size_limit_bytes();
+        if size > limit {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "the directory archives to more than the size limit of {}",
+                    format_bytes_to_readable_string(limit as i64)
+                ),
+            )
+            .into());
+        }
+        archive_format = Some(options.format.to_string());
+        kind = ShareKind::LiveArchive;
+        source_path = Some(dir.to_string_lossy().to_string());
+        archive_options = Some(options.encode());
+        source_fingerprint = Some(riptide_archive::fingerprint(&entries));
+    } else if bundle || path.is_dir() {
+        if let Some(level) = archive.level {
+            archive
+                .format
+                .check_level(level)
+                .map_err(|e| IoError::new(ErrorKind::InvalidInput, e))?;
+        }
+
+        // several paths were asked for by name, so bundling them up won't come as a surprise
+        if !bundle {
+            println!(
+                "You are attempting to share a directory. This will be archived into a {} file. Is this ok? (y/n)",
+                archive.format
+            );
+            let mut input = String::new();
+            std::io::stdin().read_line(&mut input).unwrap();
+            if input.trim() != "y" {
+                return Err(Box::new(IoError::new(
+                    ErrorKind::InvalidInput,
+                    "user cancelled directory share",
+                )));
+            }
+        }
+
+        // archive the directory, storing in tmp location
+        let entries = if bundle {
+            riptide_archive::collect_bundle_entries(paths, archive)?
+        } else {
+            riptide_archive::collect_entries(path, archive)?
+        };
+        let (mut temp_file, skipped) =
+            riptide_archive::write_archive(&entries, archive, tempfile()?)?;
+        temp_file.seek(SeekFrom::Start(0))?;
+        let files = entries
+            .iter()
+            .filter(|e| e.kind != riptide_archive::EntryKind::Directory)
+            .count();
+        println!("Archived {} file(s)", files - skipped);
+        if skipped > 0 {
+            println!("{} file(s) could not be read and were skipped", skipped);
+        }
+
+        file_name = format!(
+            "{}.{}",
+            if bundle {
+                OsStr::new("bundle")
+            } else {
+                path.file_name()
+                    .unwrap_or_else(|| OsStr::new("unnamed_directory"))
+            }
+            .to_string_lossy(),
+            archive.format
+        );
+        file = Some(Box::new(temp_file));
+        archive_format = Some(archive.format.to_string());
     } else {
         file_name = path
             .file_name()
             .unwrap_or_else(|| OsStr::new("unnamed_file"))
             .to_string_lossy()
             .to_string();
-        file = File::open(path)?;
+        file = Some(Box::new(File::open(path)?));
     }
 
-    trace!("getting file size");
-    let size = file.metadata()?.len();
+    // archives keep their extension when renamed, so that they can still be opened
+    if let Some(name) = &options.name {
+        file_name = match &archive_format {
+            Some(format) if !name.ends_with(&format!(".{}", format)) => {
+                format!("{}.{}", name, format)
+            }
+            _ => name.clone(),
+        };
+    }
 
     let id: u32 = rand::thread_rng().gen(); //XXX: we should check that it's not already in use
 
-    // Copying the file to a new location, so that it can be deleted after the share is complete
-    trace!("copying file to new location");
-    let mut output_file = std::fs::File::create(format!(
-        "{}/{}",
-        CONFIG.file_store_location().to_string_lossy(),
-        id
-    ))?;
-    std::io::copy(&mut file, &mut output_file)?;
+    if let Some(file) = file {
+        // Copying the file to a new location, so that it can be deleted after the share is complete.
+        // The size is counted as it's copied, as there's no knowing how much will be piped in on stdin
+        trace!("copying file to new location");
+        let location = CONFIG.file_store_location().join(id.to_string());
+        let mut output_file = File::create(&location)?;
+        let limit = *CONFIG.size_limit_bytes();
+        let copied = std::io::copy(&mut file.take(limit.saturating_add(1)), &mut output_file)
+            .and_then(|n| {
+                if n > limit {
+                    return Err(IoError::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "the file is larger than the size limit of {}",
+                            format_bytes_to_readable_string(limit as i64)
+                        ),
+                    ));
+                }
+                Ok(n)
+            });
+        match copied {
+            Ok(n) => size = n,
+            Err(e) => {
+                drop(output_file);
+                if let Err(e) = std::fs::remove_file(&location) {
+                    error!("Failed to remove {:?}: {}", location, e);
+                }
+                return Err(Box::new(e));
+            }
+        }
+    }
 
     trace!("setting file expiry");
     let crt = std::time::SystemTime::now()
@@ -132,6 +331,15 @@ fn create_share(
         file_size: size as i64,
         user_name: whoami::realname(),
         file_name,
+        broken_reason: None,
+        max_downloads: *max_downloads,
+        downloads: 0,
+        one_time: *one_time,
+        archive_format,
+        kind,
+        source_path,
+        archive_options,
+        source_fingerprint,
     })
 }
 
@@ -171,11 +379,11 @@ fn generate_link_url(share: &Share) -> String {
 // }
 
 fn handle_share(
-    filename: &PathBuf,
-    share_time: i64,
+    paths: &[PathBuf],
+    options: &ShareOptions,
 ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
     trace!("creating share");
-    let share: Share = create_share(filename, share_time)?;
+    let share: Share = create_share(paths, options)?;
 
     trace!("saving share to database");
     try_save_to_database(&share)?;
@@ -190,9 +398,76 @@ fn handle_share(
 
     println!("The file has been shared!");
     println!("The link to your file is {}", &link);
+    if share.one_time {
+        println!("The share will be removed as soon as it has been downloaded");
+    } else if let Some(max) = share.max_downloads {
+        println!("The share will be removed after {} download(s)", max);
+    }
     Ok(())
 }
 
+/// Share each of `paths` on its own, printing a table of their links.
+fn handle_separate_shares(
+    paths: &[PathBuf],
+    options: &ShareOptions,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    let mut rows = Vec::new();
+    let mut failed = 0;
+    for path in paths {
+        trace!("creating share for {:?}", path);
+        let res = create_share(std::slice::from_ref(path), options).and_then(|share| {
+            try_save_to_database(&share)?;
+            Ok(share)
+        });
+        match res {
+            Ok(share) => rows.push((share.file_name.clone(), generate_link_url(&share))),
+            Err(e) => {
+                failed += 1;
+                rows.push((path.display().to_string(), format!("failed: {}", e)));
+            }
+        }
+    }
+
+    println!("{0: <30} | Link", "Name");
+    println!("{:-<30}-+-{:-<40}", "", "");
+    for (name, link) in rows {
+        println!("{0: <30} | {1}", name, link);
+    }
+    if options.one_time {
+        println!("Each share will be removed as soon as it has been downloaded");
+    } else if let Some(max) = options.max_downloads {
+        println!("Each share will be removed after {} download(s)", max);
+    }
+
+    if failed > 0 {
+        return Err(format!("{} of {} file(s) could not be shared", failed, paths.len()).into());
+    }
+    Ok(())
+}
+
+/// Expand glob patterns the shell left alone, e.g. because they were quoted.
+fn expand_globs<'a>(
+    paths: impl Iterator<Item = &'a PathBuf>,
+) -> Result<Vec<PathBuf>, Box<dyn Error + Send + Sync + 'static>> {
+    let mut expanded = Vec::new();
+    for path in paths {
+        let pattern = path.to_string_lossy();
+        if path.exists() || !pattern.contains(['*', '?', '[']) {
+            expanded.push(path.clone());
+            continue;
+        }
+
+        let found = expanded.len();
+        for entry in glob::glob(&pattern)? {
+            expanded.push(entry?);
+        }
+        if expanded.len() == found {
+            return Err(format!("no files match `{}`", pattern).into());
+        }
+    }
+    Ok(expanded)
+}
+
 /// format a time to a human reable string, e.g. 10 seconds ago, 2 hours in the future
 fn format_time_relative_to_now(seconds_past_epoch: i64) -> String {
     let now = SystemTime::now();
@@ -204,28 +479,23 @@ fn format_time_relative_to_now(seconds_past_epoch: i64) -> String {
     let diff = seconds_past_epoch - now as i64;
 
     if diff < 0 {
-        //format in terms of seconds, minutes, horus, or days ago
-        let diff = diff.abs();
-        if diff < 60 {
-            format!("{} seconds ago", diff)
-        } else if diff < 60 * 60 {
-            format!("{} minutes ago", diff / 60)
-        } else if diff < 60 * 60 * 24 {
-            format!("{} hours ago", diff / (60 * 60))
-        } else {
-            format!("{} days ago", diff / (60 * 60 * 24))
-        }
+        format!("{} ago", format_duration(diff.abs()))
     } else {
-        //format in terms of seconds, minutes, hours, or days in the future
-        if diff < 60 {
-            format!("{} seconds", diff)
-        } else if diff < 60 * 60 {
-            format!("{} minutes", diff / 60)
-        } else if diff < 60 * 60 * 24 {
-            format!("{} hours", diff / (60 * 60))
-        } else {
-            format!("{} days", diff / (60 * 60 * 24))
-        }
+        format_duration(diff)
+    }
+}
+
+/// format a duration to a human readable string, e.g. 10 seconds, 2 hours
+fn format_duration(seconds: i64) -> String {
+    //format in terms of seconds, minutes, hours, or days
+    if seconds < 60 {
+        format!("{} seconds", seconds)
+    } else if seconds < 60 * 60 {
+        format!("{} minutes", seconds / 60)
+    } else if seconds < 60 * 60 * 24 {
+        format!("{} hours", seconds / (60 * 60))
+    } else {
+        format!("{} days", seconds / (60 * 60 * 24))
     }
 }
 
@@ -262,24 +532,49 @@ fn list_shares() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
 
     let shares = riptide_database::get_shares(&mut conn, &whoami::realname())?;
 
+    // if the agent is running, we can also show which shares are being uploaded right now
+    let uploading: Vec<u32> = match agent::request(Request::Uploads) {
+        Some(Response::Uploads(uploads)) => uploads.into_iter().map(|u| u.file_id).collect(),
+        _ => Vec::new(),
+    };
+
     println!(
-        "{0: <10} | {1: <20} | {2: <10} | {3: <20} | {4: <20}",
-        "ID", "Name", "Size", "Created", "Expires"
+        "{0: <10} | {1: <20} | {2: <10} | {3: <8} | {4: <20} | {5: <20} | {6: <10} | {7: <10}",
+        "ID", "Name", "Size", "Format", "Created", "Expires", "Downloads", "Status"
     );
     println!(
-        "{:-<10}-+-{:-<20}-+-{:-<10}-+-{:-<20}-+-{:-<20}",
-        "", "", "", "", ""
+        "{:-<10}-+-{:-<20}-+-{:-<10}-+-{:-<8}-+-{:-<20}-+-{:-<20}-+-{:-<10}-+-{:-<10}",
+        "", "", "", "", "", "", "", ""
     );
 
     for share in shares {
         println!(
-            "{0: <10} | {1: <20} | {2: <10} | {3: <20} | {4: <20}",
+            "{0: <10} | {1: <20} | {2: <10} | {3: <8} | {4: <20} | {5: <20} | {6: <10} | {7: <10}",
             share.file_id,
             &share.file_name[..(20.min(share.file_name.len()))],
             format_bytes_to_readable_string(share.file_size),
+            match (share.kind, share.archive_format.as_deref()) {
+                (ShareKind::LiveArchive, Some(format)) => format!("live {}", format),
+                (_, format) => format.unwrap_or("-").to_string(),
+            },
             format_time_relative_to_now(share.crt),
             format_time_relative_to_now(share.exp),
+            match share.remaining_downloads() {
+                Some(_) if share.one_time => String::from("once"),
+                Some(n) => format!("{} left", n),
+                None => String::from("unlimited"),
+            },
+            if share.broken_reason.is_some() {
+                "broken"
+            } else if uploading.contains(&(share.file_id as u32)) {
+                "uploading"
+            } else {
+                "ok"
+            },
         );
+        if let Some(reason) = share.broken_reason {
+            println!("{0: <10} | {1}", "", reason);
+        }
     }
 
     Ok(())
@@ -302,6 +597,215 @@ fn remove_share(id: u32) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
     Ok(())
 }
 
+fn list_uploads() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    let uploads = match agent::request(Request::Uploads) {
+        Some(Response::Uploads(uploads)) => uploads,
+        Some(Response::Error(e)) => return Err(e.into()),
+        _ => {
+            println!("The agent is not running, no uploads are in progress");
+            return Ok(());
+        }
+    };
+
+    if uploads.is_empty() {
+        println!("No uploads are in progress");
+        return Ok(());
+    }
+
+    println!(
+        "{0: <10} | {1: <20} | {2: <8} | {3: <24} | {4: <10}",
+        "ID", "Upload", "Attempt", "Progress", "Elapsed"
+    );
+    println!(
+        "{:-<10}-+-{:-<20}-+-{:-<8}-+-{:-<24}-+-{:-<10}",
+        "", "", "", "", ""
+    );
+    for upload in uploads {
+        println!(
+            "{0: <10} | {1: <20} | {2: <8} | {3: <24} | {4: <10}",
+            upload.file_id,
+            &upload.upload_id[..(20.min(upload.upload_id.len()))],
+            upload.attempt,
+            format!(
+                "{} / {}",
+                format_bytes_to_readable_string(upload.sent as i64),
+                format_bytes_to_readable_string(upload.total as i64)
+            ),
+            format!("{}s", upload.elapsed_seconds),
+        );
+    }
+
+    Ok(())
+}
+
+fn set_paused(paused: bool) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    let request = if paused {
+        Request::Pause
+    } else {
+        Request::Resume
+    };
+
+    match agent::request(request) {
+        Some(Response::Ok) if paused => println!("Uploads paused"),
+        Some(Response::Ok) => println!("Uploads resumed"),
+        Some(Response::Error(e)) => return Err(e.into()),
+        Some(r) => return Err(format!("unexpected response from agent: {:?}", r).into()),
+        None => println!("The agent is not running"),
+    }
+
+    Ok(())
+}
+
+fn sweep_expired() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    let removed = match agent::request(Request::SweepExpired) {
+        Some(Response::Swept(removed)) => removed,
+        Some(Response::Error(e)) => return Err(e.into()),
+        _ => {
+            // the agent isn't around to do it, so clean up ourselves
+            let mut conn = establish_connection(CONFIG.database_location())?;
+            let shares = riptide_database::remove_expired_shares(&mut conn)?;
+            for share in &shares {
+                let path = CONFIG.file_store_location().join(share.file_id.to_string());
+                match std::fs::remove_file(path) {
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    res => res?,
+                }
+            }
+            shares.len()
+        }
+    };
+
+    println!("Removed {} expired share(s)", removed);
+    Ok(())
+}
+
+/// Total size of the files in the file store.
+fn file_store_size(location: &Path) -> std::io::Result<u64> {
+    let mut total = 0;
+    for entry in std::fs::read_dir(location)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+/// Print the health of the installation and agent, returning the exit code to use.
+fn status() -> i32 {
+    println!(
+        "{0: <12} {1}",
+        "Config:",
+        Config::config_file_location().to_string_lossy()
+    );
+    let config = match Config::load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{0: <12} unable to load configuration: {1}", "", e);
+            return STATUS_NOT_CONFIGURED;
+        }
+    };
+    println!("{0: <12} {1}", "Server:", config.server_address());
+
+    if !Config::is_registered() {
+        println!("{0: <12} no", "Registered:");
+        return STATUS_NOT_CONFIGURED;
+    }
+    println!(
+        "{0: <12} yes (id {1})",
+        "Registered:",
+        config.public_id().unwrap_or_default()
+    );
+
+    match establish_connection(config.database_location())
+        .and_then(|mut conn| riptide_database::get_shares(&mut conn, &whoami::realname()))
+    {
+        Ok(shares) => {
+            let broken = shares.iter().filter(|s| s.broken_reason.is_some()).count();
+            println!(
+                "{0: <12} {1} active ({2} broken)",
+                "Shares:",
+                shares.len(),
+                broken
+            );
+        }
+        Err(e) => println!("{0: <12} unable to read database: {1}", "Shares:", e),
+    }
+    match file_store_size(config.file_store_location()) {
+        Ok(size) => println!(
+            "{0: <12} {1} in {2}",
+            "File store:",
+            format_bytes_to_readable_string(size as i64),
+            config.file_store_location().to_string_lossy()
+        ),
+        Err(e) => println!("{0: <12} unable to read file store: {1}", "File store:", e),
+    }
+
+    let status = match agent::request(Request::Status) {
+        Some(Response::Status(status)) => status,
+        Some(r) => {
+            println!("{0: <12} unexpected response: {1:?}", "Agent:", r);
+            return STATUS_AGENT_NOT_RUNNING;
+        }
+        None => {
+            println!("{0: <12} not running", "Agent:");
+            return STATUS_AGENT_NOT_RUNNING;
+        }
+    };
+    println!(
+        "{0: <12} running (pid {1}, version {2}){3}",
+        "Agent:",
+        status.pid,
+        status.version,
+        if status.shutting_down {
+            ", shutting down"
+        } else {
+            ""
+        }
+    );
+
+    if let Some(reason) = &status.rejected {
+        println!(
+            "{0: <12} rejected by server ({1}), re-register with `riptide --reset-config`",
+            "Connection:", reason
+        );
+    } else if status.connected {
+        println!(
+            "{0: <12} connected for {1} ({2} reconnects)",
+            "Connection:",
+            format_duration(status.connection_uptime_seconds as i64),
+            status.reconnects
+        );
+    } else {
+        println!(
+            "{0: <12} disconnected ({1})",
+            "Connection:",
+            status
+                .last_disconnect_reason
+                .as_deref()
+                .unwrap_or("never connected")
+        );
+    }
+    println!(
+        "{0: <12} {1} running, {2} queued{3}",
+        "Uploads:",
+        status.uploads_running,
+        status.uploads_queued,
+        if status.paused { " (paused)" } else { "" }
+    );
+    if let Some(e) = &status.last_error {
+        println!("{0: <12} {1}", "Last error:", e);
+    }
+
+    if status.rejected.is_some() {
+        STATUS_REJECTED
+    } else if status.connected {
+        STATUS_HEALTHY
+    } else {
+        STATUS_DISCONNECTED
+    }
+}
+
 #[doc(hidden)]
 fn main() {
     setup_panic!();
@@ -310,7 +814,12 @@ fn main() {
     trace!("loading cli arguments");
     let matches = cli::build_cli().get_matches();
 
-    if !Config::exists() || matches.value_of("rest-config").is_some() {
+    // status must work before the installation has been configured, so handle it before first time setup
+    if let Some(("status", _)) = matches.subcommand() {
+        std::process::exit(status());
+    }
+
+    if !Config::exists() || matches.is_present("reset-config") {
         info!("Starting first time setup, would you like to configure your installation [y/N]");
 
         let mut input = String::new();
@@ -401,20 +910,60 @@ fn main() {
             std::process::exit(1);
         }
 
-        // set the config flag to reload the riptide_agent with new details
-        if let Err(e) = Config::reload_agent() {
-            error!("Failed to set reload flag: {}", e);
+        // ask the riptide_agent to reload with new details
+        if let Err(e) = agent::reload() {
+            error!("Failed to reload agent: {}", e);
             std::process::exit(1);
         }
     }
 
-    if let Some(file) = matches.get_one::<PathBuf>("file") {
+    let files = if matches.is_present("stdin") {
+        Some(vec![PathBuf::from(STDIN_PATH)])
+    } else {
+        match matches.get_many::<PathBuf>("file").map(expand_globs) {
+            Some(Ok(files)) => Some(files),
+            Some(Err(e)) => {
+                error!("Failed to expand file arguments: {}", e);
+                std::process::exit(1);
+            }
+            None => None,
+        }
+    };
+    if let Some(files) = files {
         let time = *matches.get_one::<i64>("time").unwrap_or(&48);
-
-        trace!("file argument found: {:?}", file);
+        let max_downloads = matches.get_one::<i64>("max-downloads").copied();
+        let one_time = matches.is_present("once");
+        let archive = ArchiveOptions {
+            format: matches
+                .get_one::<String>("format")
+                .map_or(Ok(ArchiveFormat::Zip), |f| f.parse())
+                .unwrap(),
+            level: matches.get_one::<i32>("level").copied(),
+            follow_symlinks: matches.is_present("follow-symlinks"),
+            excludes: matches
+                .get_many::<String>("exclude")
+                .map(|e| e.cloned().collect())
+                .unwrap_or_default(),
+            gitignore: matches.is_present("gitignore"),
+        };
+
+        trace!("file arguments found: {:?}", files);
         trace!("time argument found: {}", time);
-
-        handle_share(file, time).unwrap();
+        trace!("max downloads argument found: {:?}", max_downloads);
+
+        let options = ShareOptions {
+            share_time: time,
+            max_downloads,
+            one_time,
+            archive,
+            live: matches.is_present("live"),
+            name: matches.get_one::<String>("name").cloned(),
+        };
+        if matches.is_present("separate") {
+            handle_separate_shares(&files, &options).unwrap();
+        } else {
+            handle_share(&files, &options).unwrap();
+        }
     } else if matches.is_present("list") {
         trace!("list argument found");
 
@@ -422,5 +971,14 @@ fn main() {
     } else if let Some(id) = matches.get_one::<u64>("remove") {
         trace!("version argument found");
         remove_share(*id as u32).unwrap();
+    } else if matches.is_present("uploads") {
+        trace!("uploads argument found");
+        list_uploads().unwrap();
+    } else if matches.is_present("pause") || matches.is_present("resume") {
+        trace!("pause/resume argument found");
+        set_paused(matches.is_present("pause")).unwrap();
+    } else if matches.is_present("sweep") {
+        trace!("sweep argument found");
+        sweep_expired().unwrap();
     }
 }
@@ -37,6 +37,88 @@ pub struct Config {
     max_upload_attempts: u64,
     size_limit_bytes: u64,
     reconnect_delay_minutes: u64,
+    #[serde(default = "default_upload_connect_timeout_seconds")]
+    upload_connect_timeout_seconds: u64,
+    #[serde(default = "default_upload_read_timeout_seconds")]
+    upload_read_timeout_seconds: u64,
+    #[serde(default = "default_max_concurrent_uploads")]
+    max_concurrent_uploads: u64,
+    #[serde(default = "default_max_concurrent_uploads_per_share")]
+    max_concurrent_uploads_per_share: u64,
+    #[serde(default)]
+    upload_rate_limit_bytes_per_second: u64,
+    #[serde(default)]
+    upload_rate_limit_per_share_bytes_per_second: u64,
+    upload_rate_limit_window_start: Option<String>,
+    upload_rate_limit_window_end: Option<String>,
+    #[serde(default = "default_shutdown_timeout_seconds")]
+    shutdown_timeout_seconds: u64,
+    #[serde(default = "default_ping_interval_seconds")]
+    ping_interval_seconds: u64,
+    #[serde(default = "default_pong_timeout_seconds")]
+    pong_timeout_seconds: u64,
+    #[serde(default = "default_idle_timeout_seconds")]
+    idle_timeout_seconds: u64,
+    #[serde(default)]
+    metrics_enabled: bool,
+    #[serde(default = "default_metrics_address")]
+    metrics_address: String,
+    #[serde(default = "default_log_level")]
+    log_level: String,
+    #[serde(default = "default_log_format")]
+    log_format: String,
+    #[serde(default)]
+    log_file_enabled: bool,
+    #[serde(default = "default_log_file_rotation")]
+    log_file_rotation: String,
+}
+
+fn default_upload_connect_timeout_seconds() -> u64 {
+    30
+}
+
+fn default_upload_read_timeout_seconds() -> u64 {
+    60
+}
+
+fn default_max_concurrent_uploads() -> u64 {
+    4
+}
+
+fn default_max_concurrent_uploads_per_share() -> u64 {
+    2
+}
+
+fn default_shutdown_timeout_seconds() -> u64 {
+    30
+}
+
+fn default_ping_interval_seconds() -> u64 {
+    30
+}
+
+fn default_pong_timeout_seconds() -> u64 {
+    10
+}
+
+fn default_idle_timeout_seconds() -> u64 {
+    120
+}
+
+fn default_metrics_address() -> String {
+    String::from("127.0.0.1:9464")
+}
+
+fn default_log_level() -> String {
+    String::from("info")
+}
+
+fn default_log_format() -> String {
+    String::from("text")
+}
+
+fn default_log_file_rotation() -> String {
+    String::from("daily")
 }
 
 /// Information required to connect to central api
@@ -287,9 +369,23 @@ impl Config {
         Ok(result)
     }
 
+    /// the location of the unix socket the agent listens on for control requests from the cli
+    pub fn control_socket_location() -> PathBuf {
+        get_config_dir().join("agent.sock")
+    }
+
+    /// the directory log files are written to, when enabled
+    pub fn log_directory() -> PathBuf {
+        get_config_dir().join("logs")
+    }
+
+    /// the location of the configuration file
+    pub fn config_file_location() -> PathBuf {
+        get_config_dir().join("riptide.conf")
+    }
+
     pub fn exists() -> bool {
-        let config_path = get_config_dir().join("riptide.conf");
-        config_path.exists()
+        Config::config_file_location().exists()
     }
 
     /// load the configuration from the disk
@@ -0,0 +1,245 @@
+//! Protocol used by the riptide cli to control a running agent over a unix domain socket.
+//!
+//! Each message is a single line of JSON, the cli sends a [`Request`] and the agent replies with exactly
+//! one [`Response`]. A connection may be used for any number of requests.
+
+#![warn(
+    missing_docs,
+    missing_debug_implementations,
+    missing_copy_implementations,
+    trivial_casts,
+    trivial_numeric_casts,
+    unsafe_code,
+    unstable_features,
+    unused_import_braces,
+    unused_qualifications,
+    deprecated
+)]
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    time::Duration,
+};
+
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+
+/// How long the cli waits on the agent before assuming it is stuck.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A request sent from the cli to the agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum Request {
+    /// Get the current state of the agent.
+    Status,
+    /// List the uploads currently running.
+    Uploads,
+    /// Reload the configuration from disk.
+    Reload,
+    /// Stop starting new uploads, any new requests are queued until resumed.
+    Pause,
+    /// Resume starting uploads.
+    Resume,
+    /// Remove expired shares now, rather than waiting for the next sweep.
+    SweepExpired,
+}
+
+/// The state of the agent, as reported by [`Request::Status`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AgentStatus {
+    /// The process id of the agent.
+    pub pid: u32,
+    /// The version of the agent.
+    pub version: String,
+    /// Whether the websocket to the Central-API is currently connected.
+    pub connected: bool,
+    /// How long the current websocket connection has been up for.
+    pub connection_uptime_seconds: u64,
+    /// The number of times the agent has reconnected since it started.
+    pub reconnects: u64,
+    /// Why the last connection ended, if there has been one.
+    pub last_disconnect_reason: Option<String>,
+    /// The most recent error encountered by the agent, if there has been one.
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// Why the server refused the agent's connection, if it has. The agent won't reconnect until re-registered.
+    #[serde(default)]
+    pub rejected: Option<String>,
+    /// Whether uploads have been paused.
+    pub paused: bool,
+    /// Whether the agent is shutting down.
+    pub shutting_down: bool,
+    /// The number of uploads currently running.
+    pub uploads_running: usize,
+    /// The number of uploads waiting to start.
+    pub uploads_queued: usize,
+}
+
+/// An upload in progress, as reported by [`Request::Uploads`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadInfo {
+    /// The share being uploaded.
+    pub file_id: u32,
+    /// The id the server gave this upload.
+    pub upload_id: String,
+    /// The current attempt, starting at one.
+    pub attempt: u64,
+    /// Bytes sent so far in the current attempt.
+    pub sent: u64,
+    /// Total bytes to send, zero until the upload has reported progress.
+    pub total: u64,
+    /// How long ago the upload was requested.
+    pub elapsed_seconds: u64,
+}
+
+/// A response sent from the agent to the cli.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "response", content = "data", rename_all = "snake_case")]
+pub enum Response {
+    /// The request was carried out.
+    Ok,
+    /// Reply to [`Request::Status`].
+    Status(AgentStatus),
+    /// Reply to [`Request::Uploads`].
+    Uploads(Vec<UploadInfo>),
+    /// Reply to [`Request::SweepExpired`], with the number of shares removed.
+    Swept(usize),
+    /// The request could not be carried out.
+    Error(String),
+}
+
+/// Errors which can occur while talking to the agent.
+#[derive(Debug)]
+pub enum ControlError {
+    /// Unable to connect to, read from, or write to the socket.
+    Io(std::io::Error),
+    /// A message could not be encoded or decoded.
+    Protocol(serde_json::Error),
+    /// The agent closed the connection without replying.
+    Closed,
+}
+
+impl From<std::io::Error> for ControlError {
+    fn from(e: std::io::Error) -> Self {
+        ControlError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ControlError {
+    fn from(e: serde_json::Error) -> Self {
+        ControlError::Protocol(e)
+    }
+}
+
+impl std::fmt::Display for ControlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControlError::Io(e) => write!(f, "Unable to communicate with agent: {}", e),
+            ControlError::Protocol(e) => write!(f, "Invalid message from agent: {}", e),
+            ControlError::Closed => write!(f, "Agent closed the connection"),
+        }
+    }
+}
+
+impl std::error::Error for ControlError {}
+
+/// Encode a message as a single line, ready to be written to the socket.
+pub fn encode<T: serde::Serialize>(message: &T) -> Result<Vec<u8>, ControlError> {
+    let mut data = serde_json::to_vec(message)?;
+    data.push(b'\n');
+    Ok(data)
+}
+
+/// Decode a message from a single line read from the socket.
+pub fn decode<T: DeserializeOwned>(line: &str) -> Result<T, ControlError> {
+    Ok(serde_json::from_str(line.trim_end())?)
+}
+
+/// A blocking connection to the agent, used by the cli.
+#[derive(Debug)]
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Client {
+    /// Connect to the agent listening on the socket at `path`.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Client, ControlError> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        Ok(Client {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    /// Send a request to the agent, and wait for its response.
+    pub fn request(&mut self, request: Request) -> Result<Response, ControlError> {
+        self.writer.write_all(&encode(&request)?)?;
+
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(ControlError::Closed);
+        }
+        decode(&line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixListener,
+    };
+
+    use super::{decode, encode, Client, Request, Response, UploadInfo};
+
+    #[test]
+    fn test_round_trip() {
+        let response = Response::Uploads(vec![UploadInfo {
+            file_id: 42,
+            upload_id: String::from("abc"),
+            attempt: 1,
+            sent: 10,
+            total: 100,
+            elapsed_seconds: 3,
+        }]);
+
+        let data = encode(&response).unwrap();
+        assert_eq!(data.iter().filter(|b| **b == b'\n').count(), 1);
+        let decoded: Response = decode(std::str::from_utf8(&data).unwrap()).unwrap();
+        assert_eq!(decoded, response);
+    }
+
+    #[test]
+    fn test_client() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            for line in BufReader::new(stream).lines() {
+                let response = match decode(&line.unwrap()).unwrap() {
+                    Request::SweepExpired => Response::Swept(2),
+                    _ => Response::Ok,
+                };
+                writer.write_all(&encode(&response).unwrap()).unwrap();
+            }
+        });
+
+        let mut client = Client::connect(&path).unwrap();
+        assert_eq!(client.request(Request::Pause).unwrap(), Response::Ok);
+        assert_eq!(
+            client.request(Request::SweepExpired).unwrap(),
+            Response::Swept(2)
+        );
+        drop(client);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1 @@
+ALTER TABLE shares ADD COLUMN broken_reason TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE shares DROP COLUMN downloads;
+ALTER TABLE shares DROP COLUMN max_downloads;
@@ -0,0 +1,2 @@
+ALTER TABLE shares ADD COLUMN max_downloads INTEGER;
+ALTER TABLE shares ADD COLUMN downloads INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE shares ADD COLUMN one_time BOOLEAN NOT NULL DEFAULT 0;
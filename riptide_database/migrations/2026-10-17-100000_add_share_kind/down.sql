@@ -0,0 +1,3 @@
+ALTER TABLE shares DROP COLUMN archive_options;
+ALTER TABLE shares DROP COLUMN source_path;
+ALTER TABLE shares DROP COLUMN kind;
@@ -0,0 +1,3 @@
+ALTER TABLE shares ADD COLUMN kind TEXT NOT NULL DEFAULT 'file';
+ALTER TABLE shares ADD COLUMN source_path TEXT;
+ALTER TABLE shares ADD COLUMN archive_options TEXT;
@@ -0,0 +1 @@
+ALTER TABLE shares ADD COLUMN source_fingerprint TEXT;
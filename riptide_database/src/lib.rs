@@ -24,25 +24,56 @@ pub mod schema;
 
 use std::time::UNIX_EPOCH;
 
-use diesel::prelude::*;
 pub use diesel::SqliteConnection;
+use diesel::{connection::SimpleConnection, prelude::*};
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use ws_com_framework::FileId;
 
-pub use crate::models::Share;
+pub use crate::models::{Share, ShareKind};
 
 /// migration to initalise the database
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
+/// How long to wait for another process (e.g. the cli while the agent is running) to release its lock
+const BUSY_TIMEOUT_MS: u32 = 5000;
+
 /// Create a new connection pool to the database
+///
+/// The database is switched to WAL mode so that readers and a writer don't block each other, and waits
+/// for locks held by other processes rather than failing with `database is locked`.
 pub fn establish_connection(
     database_url: &str,
 ) -> Result<SqliteConnection, Box<dyn std::error::Error + Send + Sync + 'static>> {
     let mut conn = SqliteConnection::establish(database_url)?;
+    conn.batch_execute(&format!(
+        "PRAGMA busy_timeout = {}; PRAGMA journal_mode = WAL;",
+        BUSY_TIMEOUT_MS
+    ))?;
     conn.exclusive_transaction(move |conn| conn.run_pending_migrations(MIGRATIONS).map(|_| ()))?;
     Ok(conn)
 }
 
+/// Whether an error returned by a query means the connection can no longer be used, rather than there being
+/// something wrong with the query itself.
+pub fn is_connection_error(e: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    use diesel::result::{DatabaseErrorKind, Error};
+
+    match e.downcast_ref::<Error>() {
+        Some(Error::DatabaseError(DatabaseErrorKind::ClosedConnection, _)) => true,
+        // sqlite has no more specific kind for problems with the database file
+        Some(Error::DatabaseError(DatabaseErrorKind::Unknown, info)) => [
+            "disk I/O error",
+            "unable to open database file",
+            "database disk image is malformed",
+            "file is not a database",
+        ]
+        .iter()
+        .any(|m| info.message().contains(m)),
+        Some(Error::BrokenTransactionManager) => true,
+        _ => false,
+    }
+}
+
 /// Insert a new share into the database
 pub fn insert_share(
     conn: &mut SqliteConnection,
@@ -115,6 +146,60 @@ pub fn remove_share(
     Ok(())
 }
 
+/// Mark a share as broken, recording why its file can no longer be served
+pub fn mark_share_broken(
+    conn: &mut SqliteConnection,
+    id: u32,
+    reason: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    use schema::shares::dsl::*;
+    diesel::update(shares.filter(file_id.eq(id as i64)))
+        .set(broken_reason.eq(Some(reason)))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Clear the reason a share was marked as broken, now that its file can be served again
+pub fn clear_share_broken(
+    conn: &mut SqliteConnection,
+    id: u32,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    use schema::shares::dsl::*;
+    diesel::update(shares.filter(file_id.eq(id as i64)))
+        .set(broken_reason.eq(None::<String>))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Record a completed download of a share.
+///
+/// If this used up the last download allowed, the share is removed from the database and returned so
+/// that its file can be cleaned up.
+pub fn record_download(
+    conn: &mut SqliteConnection,
+    id: u32,
+) -> Result<Option<Share>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    use schema::shares::dsl::*;
+
+    let exhausted = conn.immediate_transaction(|conn| {
+        diesel::update(shares.filter(file_id.eq(id as i64)))
+            .set(downloads.eq(downloads + 1))
+            .execute(conn)?;
+
+        diesel::delete(
+            shares
+                .filter(file_id.eq(id as i64))
+                .filter(max_downloads.le(downloads.nullable())),
+        )
+        .returning(shares::all_columns())
+        .get_results::<Share>(conn)
+    })?;
+
+    Ok(exhausted.into_iter().next())
+}
+
 /// Attempt to remove all shares from the database
 pub fn remove_all_shares(
     conn: &mut SqliteConnection,
@@ -3,7 +3,53 @@
 #![allow(unused_qualifications)]
 
 use super::schema::*;
-use diesel::Insertable;
+use diesel::{
+    backend::Backend,
+    deserialize::{self, FromSql, FromSqlRow},
+    expression::AsExpression,
+    serialize::{self, IsNull, Output, ToSql},
+    sql_types::Text,
+    sqlite::Sqlite,
+    Insertable,
+};
+
+/// How the content of a share is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsExpression, FromSqlRow)]
+#[diesel(sql_type = Text)]
+pub enum ShareKind {
+    /// A copy of the file is kept in the file store
+    File,
+    /// A directory which is archived as it is uploaded, nothing is kept in the file store
+    LiveArchive,
+}
+
+impl ShareKind {
+    /// The name the kind is stored under in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShareKind::File => "file",
+            ShareKind::LiveArchive => "live_archive",
+        }
+    }
+}
+
+impl ToSql<Text, Sqlite> for ShareKind {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Sqlite>) -> serialize::Result {
+        out.set_value(self.as_str());
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Sqlite> for ShareKind {
+    fn from_sql(bytes: <Sqlite as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        let kind = <String as FromSql<Text, Sqlite>>::from_sql(bytes)?;
+        match kind.as_str() {
+            "file" => Ok(ShareKind::File),
+            "live_archive" => Ok(ShareKind::LiveArchive),
+            other => Err(format!("unknown share kind `{}`", other).into()),
+        }
+    }
+}
 
 /// A unique share representing a file
 
@@ -22,4 +68,34 @@ pub struct Share {
     pub user_name: String,
     /// The name of the file
     pub file_name: String,
+    /// Why the stored file can no longer be served, if it has gone missing or been damaged
+    pub broken_reason: Option<String>,
+    /// The number of times the file may be downloaded, if limited
+    pub max_downloads: Option<i64>,
+    /// The number of times the file has been downloaded
+    pub downloads: i64,
+    /// Whether the share is removed as soon as the file has been downloaded once
+    pub one_time: bool,
+    /// The format the shared directory was archived in, `None` if a single file was shared
+    pub archive_format: Option<String>,
+    /// How the content of the share is stored
+    pub kind: ShareKind,
+    /// The directory a live archive is generated from
+    pub source_path: Option<String>,
+    /// The options a live archive is generated with, as encoded by `riptide_archive`
+    pub archive_options: Option<String>,
+    /// A fingerprint of the files a live archive was shared with, as made by `riptide_archive::fingerprint`
+    pub source_fingerprint: Option<String>,
+}
+
+impl Share {
+    /// The number of downloads left before the share is removed, `None` if unlimited
+    pub fn remaining_downloads(&self) -> Option<i64> {
+        let max_downloads = if self.one_time {
+            Some(1)
+        } else {
+            self.max_downloads
+        };
+        max_downloads.map(|max| max.saturating_sub(self.downloads).max(0))
+    }
 }
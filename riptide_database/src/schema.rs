@@ -6,5 +6,14 @@ table! {
         file_size -> BigInt,
         user_name -> Text,
         file_name -> Text,
+        broken_reason -> Nullable<Text>,
+        max_downloads -> Nullable<BigInt>,
+        downloads -> BigInt,
+        one_time -> Bool,
+        archive_format -> Nullable<Text>,
+        kind -> Text,
+        source_path -> Nullable<Text>,
+        archive_options -> Nullable<Text>,
+        source_fingerprint -> Nullable<Text>,
     }
 }